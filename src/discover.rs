@@ -0,0 +1,185 @@
+use crate::evdev::{self, Device, InputId};
+use crate::evenger;
+use crate::foreign::*;
+use nix::errno::Errno;
+use std::path::{Path, PathBuf};
+
+const INPUT_DIR: &str = "/dev/input";
+
+/// Codes worth pointing out when describing a device.
+const NOTABLE_CODES: &[(u32, u32)] = &[
+    (EV_KEY, BTN_LEFT), (EV_KEY, BTN_RIGHT), (EV_KEY, BTN_MIDDLE),
+    (EV_KEY, BTN_SIDE), (EV_KEY, BTN_EXTRA), (EV_KEY, BTN_TASK),
+    (EV_KEY, BTN_TOUCH), (EV_KEY, BTN_STYLUS), (EV_KEY, BTN_SOUTH),
+    (EV_KEY, BTN_TRIGGER),
+    (EV_REL, REL_X), (EV_REL, REL_Y), (EV_REL, REL_WHEEL), (EV_REL, REL_HWHEEL),
+    (EV_ABS, ABS_X), (EV_ABS, ABS_Y), (EV_ABS, ABS_Z), (EV_ABS, ABS_RX),
+    (EV_ABS, ABS_RY), (EV_ABS, ABS_MT_POSITION_X),
+    (EV_LED, LED_CAPSL), (EV_LED, LED_NUML), (EV_LED, LED_SCROLLL),
+    (EV_SW, SW_LID), (EV_SW, SW_TABLET_MODE),
+];
+
+/// An input device node and what could be learned about it.
+pub struct InputNode {
+    pub path: PathBuf,
+    pub info: Result<DeviceInfo, Errno>,
+}
+
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub phys: Option<String>,
+    pub uniq: Option<String>,
+    pub id: InputId,
+    pub types: Vec<u32>,
+    pub properties: Vec<u32>,
+    /// Number of keyboard keys, excluding buttons.
+    pub keys: usize,
+    pub notable: Vec<(u32, u32)>,
+    /// Whether some process holds an exclusive grab on the device, if
+    /// checked.
+    pub grabbed: Option<bool>,
+    /// Whether the device is a virtual device created by evenger.
+    pub own: bool,
+}
+
+/// Lists the `/dev/input/event*` nodes, in numerical order. See `probe` for
+/// `probe_grab`.
+pub fn scan(probe_grab: bool) -> std::io::Result<Vec<InputNode>> {
+    let mut paths: Vec<(u32, PathBuf)> = std::fs::read_dir(INPUT_DIR)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let num = name.strip_prefix("event")?.parse::<u32>().ok()?;
+            Some((num, entry.path()))
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths.into_iter()
+        .map(|(_, path)| {
+            let info = probe(&path, probe_grab);
+            InputNode { path, info }
+        })
+        .collect())
+}
+
+/// Opens a device node and collects information about the device. With
+/// `probe_grab`, whether the device is grabbed is checked by grabbing it,
+/// which briefly takes its input away from everyone else.
+pub fn probe<P: AsRef<Path>>(path: P, probe_grab: bool) -> Result<DeviceInfo, Errno> {
+    use nix::fcntl::OFlag;
+    use nix::sys::stat::Mode;
+
+    let fd = nix::fcntl::open(
+        path.as_ref(),
+        OFlag::O_CLOEXEC | OFlag::O_NONBLOCK,
+        Mode::empty()
+    ).map_err(|e| e.as_errno().unwrap_or(Errno::UnknownErrno))?;
+
+    let dev = match Device::new_from_fd(fd) {
+        Ok(dev) => dev,
+        Err(e) => {
+            let _ = nix::unistd::close(fd);
+            return Err(e);
+        },
+    };
+
+    let grabbed = match probe_grab {
+        true => match dev.grab(true) {
+            Ok(()) => {
+                let _ = dev.grab(false);
+                Some(false)
+            },
+            Err(Errno::EBUSY) => Some(true),
+            Err(_) => None,
+        },
+        false => None,
+    };
+
+    let types = (0..EV_CNT)
+        .filter(|&type_| type_ != EV_SYN && dev.has_event_type(type_))
+        .collect();
+    let properties = (0..INPUT_PROP_CNT)
+        .filter(|&prop| dev.has_property(prop))
+        .collect();
+    let keys = (KEY_ESC..BTN_MISC)
+        .filter(|&code| dev.has_event_code(EV_KEY, code))
+        .count();
+    let notable = NOTABLE_CODES.iter()
+        .cloned()
+        .filter(|&(type_, code)| dev.has_event_code(type_, code))
+        .collect();
+
+    let name = dev.name();
+    let id = dev.id();
+    let own = evenger::is_virtual_device(name.as_deref(), id);
+
+    Ok(DeviceInfo {
+        name,
+        phys: dev.phys(),
+        uniq: dev.uniq(),
+        id,
+        types,
+        properties,
+        keys,
+        notable,
+        grabbed,
+        own,
+    })
+}
+
+/// Prints the input devices, for `evenger list`.
+pub fn print_list(probe_grab: bool) -> std::io::Result<()> {
+    for node in scan(probe_grab)? {
+        let info = match node.info {
+            Ok(info) => info,
+            Err(e) => {
+                println!("{}: {}", node.path.display(), e.desc());
+                continue;
+            },
+        };
+
+        println!("{}: {}", node.path.display(), info.name.as_deref().unwrap_or("(unnamed)"));
+        println!("    id: bus 0x{:04x} vendor 0x{:04x} product 0x{:04x} version 0x{:04x}",
+            info.id.bustype, info.id.vendor, info.id.product, info.id.version);
+        if let Some(phys) = info.phys.as_deref().filter(|s| !s.is_empty()) {
+            println!("    phys: {}", phys);
+        }
+        if let Some(uniq) = info.uniq.as_deref().filter(|s| !s.is_empty()) {
+            println!("    uniq: {}", uniq);
+        }
+
+        let types: Vec<&str> = info.types.iter()
+            .map(|&type_| evdev::event_type_name(type_).unwrap_or("?"))
+            .collect();
+        println!("    events: {}", types.join(" "));
+
+        if !info.properties.is_empty() {
+            let properties: Vec<&str> = info.properties.iter()
+                .map(|&prop| evdev::property_name(prop).unwrap_or("?"))
+                .collect();
+            println!("    properties: {}", properties.join(" "));
+        }
+
+        let mut notable: Vec<String> = Vec::new();
+        if info.keys > 0 {
+            notable.push(format!("{} keys", info.keys));
+        }
+        notable.extend(info.notable.iter()
+            .map(|&(type_, code)| evdev::event_code_name(type_, code)
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("{}:{}", type_, code))));
+        if !notable.is_empty() {
+            println!("    notable: {}", notable.join(" "));
+        }
+
+        if info.grabbed == Some(true) {
+            println!("    grabbed by some process");
+        }
+        if info.own {
+            println!("    evenger virtual device");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,220 @@
+use crate::discover;
+use crate::evenger::Config;
+use nix::errno::Errno;
+use nix::unistd::{access, AccessFlags};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/99-evenger.rules";
+
+/// Findings of the diagnostics, along with suggested fixes.
+#[derive(Default)]
+struct Report {
+    problems: usize,
+    fixes: Vec<String>,
+}
+
+/// Checks the environment for common setup problems, for `evenger doctor`.
+/// Returns whether everything looks fine.
+pub fn run<P: AsRef<Path>>(config: P) -> bool {
+    let mut report = Report::default();
+
+    check_uinput(&mut report);
+    check_input_nodes(&mut report);
+    check_config(&mut report, config.as_ref());
+
+    if report.problems == 0 {
+        println!("\nno problems found");
+        return true;
+    }
+
+    println!("\n{} problem(s) found", report.problems);
+    if !report.fixes.is_empty() {
+        println!("\npossible fixes:");
+        for fix in &report.fixes {
+            println!("\n{}", fix);
+        }
+    }
+
+    false
+}
+
+impl Report {
+    fn ok(&self, msg: &str) {
+        println!("ok:      {}", msg);
+    }
+
+    fn problem(&mut self, msg: &str) {
+        println!("problem: {}", msg);
+        self.problems += 1;
+    }
+
+    fn note(&self, msg: &str) {
+        println!("note:    {}", msg);
+    }
+
+    fn fix<S: Into<String>>(&mut self, fix: S) {
+        let fix = fix.into();
+        if !self.fixes.contains(&fix) {
+            self.fixes.push(fix);
+        }
+    }
+}
+
+fn check_uinput(report: &mut Report) {
+    let meta = match std::fs::metadata(UINPUT_PATH) {
+        Ok(meta) => meta,
+        Err(_) => {
+            report.problem(&format!("{} does not exist", UINPUT_PATH));
+            match uinput_module_state() {
+                Some(state) => report.note(&format!("uinput module is {}", state)),
+                None => report.note("uinput module is not available for the running kernel"),
+            }
+            report.fix(
+                "    load the uinput module now and on every boot:\n\
+                 \n        modprobe uinput\n\
+                 \n        echo uinput > /etc/modules-load.d/uinput.conf");
+            return;
+        },
+    };
+
+    match access(UINPUT_PATH, AccessFlags::R_OK | AccessFlags::W_OK) {
+        Ok(()) => report.ok(&format!("{} is accessible", UINPUT_PATH)),
+        Err(e) => {
+            report.problem(&format!("{} is not accessible ({}): {}",
+                UINPUT_PATH, describe_node(&meta), errno_desc(e)));
+            suggest_group(report, meta.gid());
+            report.fix(format!(
+                "    let the group access {} with a udev rule, e.g. in {}:\n\
+                 \n        KERNEL==\"uinput\", MODE=\"0660\", GROUP=\"input\", OPTIONS+=\"static_node=uinput\"\n\
+                 \n    then run `udevadm control --reload && udevadm trigger`",
+                UINPUT_PATH, UDEV_RULES_PATH));
+        },
+    }
+}
+
+fn check_input_nodes(report: &mut Report) {
+    let nodes = match discover::scan(false) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            report.problem(&format!("can't list input devices: {}", e));
+            return;
+        },
+    };
+
+    let mut denied = 0;
+    for node in &nodes {
+        match &node.info {
+            Ok(_) => {},
+            Err(Errno::EACCES) | Err(Errno::EPERM) => {
+                denied += 1;
+                match std::fs::metadata(&node.path) {
+                    Ok(meta) => {
+                        report.problem(&format!("{} is not readable ({})",
+                            node.path.display(), describe_node(&meta)));
+                        if denied == 1 {
+                            suggest_group(report, meta.gid());
+                        }
+                    },
+                    Err(e) => report.problem(&format!("{} is not readable, nor can it be \
+                        inspected: {}", node.path.display(), e)),
+                }
+            },
+            Err(e) => report.problem(&format!("{}: {}", node.path.display(), e.desc())),
+        }
+    }
+
+    if nodes.is_empty() {
+        report.problem("no input devices found in /dev/input");
+    } else if denied == 0 {
+        report.ok(&format!("{} input devices are readable", nodes.len()));
+    }
+}
+
+fn check_config(report: &mut Report, path: &Path) {
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            report.problem(&format!("configuration: {}", e));
+            return;
+        },
+    };
+    report.ok(&format!("{} is valid", path.display()));
+
+    for dev in &config.devices {
+        match discover::probe(&dev.path, true) {
+            Ok(info) => {
+                if info.grabbed == Some(true) {
+                    report.problem(&format!("device {} ({}) is grabbed by some process",
+                        dev.id, dev.path.display()));
+                    report.fix(
+                        "    stop the process grabbing the device, e.g. a running\n    \
+                         evenger instance; `fuser -v <device>` shows who has it open");
+                } else {
+                    report.ok(&format!("device {} ({}) can be opened", dev.id, dev.path.display()));
+                }
+            },
+            Err(e) => report.problem(&format!("device {} ({}): {}",
+                dev.id, dev.path.display(), e.desc())),
+        }
+    }
+}
+
+/// Suggests joining the group owning a device node, if the user isn't in it.
+fn suggest_group(report: &mut Report, gid: u32) {
+    let in_group = nix::unistd::getegid().as_raw() == gid
+        || nix::unistd::getgroups()
+            .map(|groups| groups.iter().any(|g| g.as_raw() == gid))
+            .unwrap_or(false);
+    if in_group || gid == 0 {
+        return;
+    }
+
+    let group = group_name(gid).unwrap_or_else(|| gid.to_string());
+    report.note(&format!("you are not a member of group {}", group));
+    report.fix(format!(
+        "    join the {} group and log in again:\n\
+         \n        usermod -aG {} $USER", group, group));
+}
+
+fn describe_node(meta: &std::fs::Metadata) -> String {
+    let group = group_name(meta.gid()).unwrap_or_else(|| meta.gid().to_string());
+    format!("mode {:o}, group {}", meta.mode() & 0o777, group)
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    let groups = std::fs::read_to_string("/etc/group").ok()?;
+    groups.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[2] == gid.to_string())
+        .map(|fields| fields[0].to_string())
+}
+
+/// Tells whether uinput is loaded, built in or loadable, if at all.
+fn uinput_module_state() -> Option<&'static str> {
+    if Path::new("/sys/module/uinput").exists() {
+        return Some("loaded");
+    }
+
+    let release = nix::sys::utsname::uname().release().to_string();
+    let modules = Path::new("/lib/modules").join(release);
+    let listed = |file: &str, needle: &str| std::fs::read_to_string(modules.join(file))
+        .map(|text| text.lines().any(|line| line.contains(needle)))
+        .unwrap_or(false);
+
+    if listed("modules.builtin", "/uinput.ko") {
+        Some("built in")
+    } else if listed("modules.dep", "/uinput.ko") {
+        Some("available but not loaded")
+    } else {
+        None
+    }
+}
+
+fn errno_desc(error: nix::Error) -> &'static str {
+    match error.as_errno() {
+        Some(errno) => errno.desc(),
+        None => "unknown error",
+    }
+}
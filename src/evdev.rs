@@ -1,7 +1,8 @@
 
+use crate::foreign::*;
 use evdev_sys::*;
 use nix::errno::Errno;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::os::unix::io::RawFd;
 
 type Result<T> = std::result::Result<T, nix::errno::Errno>;
@@ -11,6 +12,26 @@ pub struct UInputDevice(*mut libevdev_uinput);
 
 pub struct InputEvent(input_event);
 
+/// Identifiers of a device, as in `struct input_id`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InputId {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+/// Range and state of an absolute axis, as in `struct input_absinfo`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
 #[allow(unused)]
 #[repr(i32)]
 pub enum ReadFlag {
@@ -93,7 +114,7 @@ impl Device {
             libevdev_enable_event_code(self.0, type_, code, 0 as *const _)
         } {
             0 => {},
-            -1 => println!("warning: cannot enable input code {:?}", code),
+            -1 => warn!("cannot enable input code {:?}", code),
             _ => {},
         }
     }
@@ -102,6 +123,207 @@ impl Device {
         let cstr = CString::new(name).unwrap();
         unsafe { libevdev_set_name(self.0, cstr.as_ptr()) };
     }
+
+    pub fn name(&self) -> Option<String> {
+        unsafe { string_from_ptr(libevdev_get_name(self.0)) }
+    }
+
+    pub fn phys(&self) -> Option<String> {
+        unsafe { string_from_ptr(libevdev_get_phys(self.0)) }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_phys<T: Into<Vec<u8>>>(&mut self, phys: T) {
+        let cstr = CString::new(phys).unwrap();
+        unsafe { libevdev_set_phys(self.0, cstr.as_ptr()) };
+    }
+
+    pub fn uniq(&self) -> Option<String> {
+        unsafe { string_from_ptr(libevdev_get_uniq(self.0)) }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_uniq<T: Into<Vec<u8>>>(&mut self, uniq: T) {
+        let cstr = CString::new(uniq).unwrap();
+        unsafe { libevdev_set_uniq(self.0, cstr.as_ptr()) };
+    }
+
+    pub fn id(&self) -> InputId {
+        unsafe {
+            InputId {
+                bustype: libevdev_get_id_bustype(self.0) as u16,
+                vendor: libevdev_get_id_vendor(self.0) as u16,
+                product: libevdev_get_id_product(self.0) as u16,
+                version: libevdev_get_id_version(self.0) as u16,
+            }
+        }
+    }
+
+    pub fn set_id(&mut self, id: InputId) {
+        unsafe {
+            libevdev_set_id_bustype(self.0, id.bustype as i32);
+            libevdev_set_id_vendor(self.0, id.vendor as i32);
+            libevdev_set_id_product(self.0, id.product as i32);
+            libevdev_set_id_version(self.0, id.version as i32);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn driver_version(&self) -> i32 {
+        unsafe { libevdev_get_driver_version(self.0) }
+    }
+
+    pub fn has_event_type(&self, type_: u32) -> bool {
+        unsafe { libevdev_has_event_type(self.0, type_) == 1 }
+    }
+
+    pub fn has_event_code(&self, type_: u32, code: u32) -> bool {
+        unsafe { libevdev_has_event_code(self.0, type_, code) == 1 }
+    }
+
+    #[allow(dead_code)]
+    pub fn enable_event_type(&mut self, type_: u32) -> Result<()> {
+        match unsafe { libevdev_enable_event_type(self.0, type_) } {
+            0 => Ok(()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Enables an absolute axis. Unlike other event codes, axes require
+    /// their range.
+    pub fn enable_abs_event(&mut self, code: u32, info: &AbsInfo) -> Result<()> {
+        let raw: input_absinfo = (*info).into();
+        match unsafe {
+            libevdev_enable_event_code(self.0, EV_ABS, code, &raw as *const _ as *const _)
+        } {
+            0 => Ok(()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    pub fn abs_info(&self, code: u32) -> Option<AbsInfo> {
+        let ptr = unsafe { libevdev_get_abs_info(self.0, code) };
+        match ptr.is_null() {
+            true => None,
+            false => Some(unsafe { *ptr }.into()),
+        }
+    }
+
+    /// Changes the axis range known to libevdev, without affecting the
+    /// kernel device.
+    #[allow(dead_code)]
+    pub fn set_abs_info(&mut self, code: u32, info: &AbsInfo) {
+        let raw: input_absinfo = (*info).into();
+        unsafe { libevdev_set_abs_info(self.0, code, &raw) };
+    }
+
+    /// Changes the axis range of the kernel device.
+    #[allow(dead_code)]
+    pub fn kernel_set_abs_info(&mut self, code: u32, info: &AbsInfo) -> Result<()> {
+        let raw: input_absinfo = (*info).into();
+        match unsafe { libevdev_kernel_set_abs_info(self.0, code, &raw) } {
+            0 => Ok(()),
+            neg_errno => Err(Errno::from_i32(-neg_errno)),
+        }
+    }
+
+    pub fn has_property(&self, prop: u32) -> bool {
+        unsafe { libevdev_has_property(self.0, prop) == 1 }
+    }
+
+    #[allow(dead_code)]
+    pub fn enable_property(&mut self, prop: u32) -> Result<()> {
+        match unsafe { libevdev_enable_property(self.0, prop) } {
+            0 => Ok(()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Returns the key repeat delay and period in milliseconds, if the
+    /// device repeats keys.
+    #[allow(dead_code)]
+    pub fn repeat(&self) -> Option<(i32, i32)> {
+        let (mut delay, mut period) = (0, 0);
+        match unsafe { libevdev_get_repeat(self.0, &mut delay, &mut period) } {
+            0 => Some((delay, period)),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_repeat(&mut self, delay: i32, period: i32) -> Result<()> {
+        for (code, value) in &[(REP_DELAY, delay), (REP_PERIOD, period)] {
+            match unsafe {
+                libevdev_enable_event_code(self.0, EV_REP, *code, value as *const i32 as *const _)
+            } {
+                0 => {},
+                _ => return Err(Errno::EINVAL),
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns an LED of the kernel device on or off.
+    #[allow(dead_code)]
+    pub fn set_led(&mut self, code: u32, on: bool) -> Result<()> {
+        let value = match on {
+            true => LIBEVDEV_LED_ON,
+            false => LIBEVDEV_LED_OFF,
+        };
+        match unsafe { libevdev_kernel_set_led_value(self.0, code, value) } {
+            0 => Ok(()),
+            neg_errno => Err(Errno::from_i32(-neg_errno)),
+        }
+    }
+
+    /// Creates a new device, not backed by any file, with the identity,
+    /// properties and capabilities of this one.
+    #[allow(dead_code)]
+    pub fn duplicate(&self) -> Result<Device> {
+        let mut dev = Device::new()?;
+
+        if let Some(name) = self.name() {
+            dev.set_name(name);
+        }
+        if let Some(phys) = self.phys() {
+            dev.set_phys(phys);
+        }
+        if let Some(uniq) = self.uniq() {
+            dev.set_uniq(uniq);
+        }
+        dev.set_id(self.id());
+
+        for prop in 0..INPUT_PROP_CNT {
+            if self.has_property(prop) {
+                dev.enable_property(prop)?;
+            }
+        }
+
+        for type_ in 0..EV_CNT {
+            if !self.has_event_type(type_) {
+                continue;
+            }
+            dev.enable_event_type(type_)?;
+
+            match type_ {
+                EV_ABS => for code in 0..ABS_CNT {
+                    if let Some(info) = self.abs_info(code) {
+                        dev.enable_abs_event(code, &info)?;
+                    }
+                },
+                EV_REP => if let Some((delay, period)) = self.repeat() {
+                    dev.set_repeat(delay, period)?;
+                },
+                _ => for code in 0..=event_type_max(type_).unwrap_or(0) {
+                    if self.has_event_code(type_, code) {
+                        dev.enable_event(type_, code);
+                    }
+                },
+            }
+        }
+
+        Ok(dev)
+    }
 }
 
 impl Drop for Device {
@@ -143,19 +365,21 @@ impl Drop for UInputDevice {
 }
 
 impl InputEvent {
-    // fn new() -> Self {
-    //     InputEvent(
-    //         input_event {
-    //             time: timeval {
-    //                 tv_sec: 0,
-    //                 tv_usec: 0,
-    //             },
-    //             type_: 0,
-    //             code: 0,
-    //             value: 0,
-    //         }
-    //     )
-    // }
+    /// Makes up an event, for tests to feed in what devices would report.
+    #[cfg(test)]
+    pub fn new(type_: u32, code: u32, value: i32) -> Self {
+        InputEvent(
+            input_event {
+                time: timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                type_: type_ as u16,
+                code: code as u16,
+                value,
+            }
+        )
+    }
 
     unsafe fn uninitialized() -> Self {
         InputEvent(std::mem::uninitialized())
@@ -177,3 +401,79 @@ impl InputEvent {
         self.0.value
     }
 }
+
+pub fn event_type_name(type_: u32) -> Option<&'static str> {
+    let ptr = unsafe { libevdev_event_type_get_name(type_) };
+    if ptr.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+    }
+}
+
+pub fn event_code_name(type_: u32, code: u32) -> Option<&'static str> {
+    let ptr = unsafe { libevdev_event_code_get_name(type_, code) };
+    if ptr.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+    }
+}
+
+pub fn event_code_from_name(type_: u32, name: &str) -> Option<u32> {
+    let cstr = CString::new(name).ok()?;
+    match unsafe { libevdev_event_code_from_name(type_, cstr.as_ptr()) } {
+        -1 => None,
+        code => Some(code as u32),
+    }
+}
+
+impl From<input_absinfo> for AbsInfo {
+    fn from(raw: input_absinfo) -> Self {
+        AbsInfo {
+            value: raw.value,
+            minimum: raw.minimum,
+            maximum: raw.maximum,
+            fuzz: raw.fuzz,
+            flat: raw.flat,
+            resolution: raw.resolution,
+        }
+    }
+}
+
+impl From<AbsInfo> for input_absinfo {
+    fn from(info: AbsInfo) -> Self {
+        input_absinfo {
+            value: info.value,
+            minimum: info.minimum,
+            maximum: info.maximum,
+            fuzz: info.fuzz,
+            flat: info.flat,
+            resolution: info.resolution,
+        }
+    }
+}
+
+unsafe fn string_from_ptr(ptr: *const std::os::raw::c_char) -> Option<String> {
+    match ptr.is_null() {
+        true => None,
+        false => Some(CStr::from_ptr(ptr).to_string_lossy().into_owned()),
+    }
+}
+
+/// Returns the highest code of an event type.
+pub fn event_type_max(type_: u32) -> Option<u32> {
+    match unsafe { libevdev_event_type_get_max(type_) } {
+        -1 => None,
+        max => Some(max as u32),
+    }
+}
+
+pub fn property_name(prop: u32) -> Option<&'static str> {
+    let ptr = unsafe { libevdev_property_get_name(prop) };
+    if ptr.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+    }
+}
@@ -0,0 +1,312 @@
+//! Axes of virtual gamepads driven by pairs of keys, for games that only
+//! accept analog input.
+
+use crate::evdev;
+use crate::foreign::*;
+use super::DeviceId;
+use super::srcdev::Event;
+use std::time::{Duration, Instant};
+
+/// How an axis resolves both of its keys being held, "simultaneous opposing
+/// cardinal directions".
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Socd {
+    /// The key pressed last wins.
+    #[default]
+    Last,
+    /// The key pressed first wins.
+    First,
+    /// The axis returns to the center.
+    Neutral,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct AxisConfig {
+    /// The gamepad the axis belongs to.
+    pub output: DeviceId,
+    pub code: u32,
+    /// The keys pushing the axis towards its minimum and maximum, each
+    /// optionally restricted to a source device.
+    pub negative: Option<(Option<DeviceId>, u32)>,
+    pub positive: Option<(Option<DeviceId>, u32)>,
+    /// How long the axis takes to move from the center to either end.
+    pub ramp: Duration,
+    pub socd: Socd,
+}
+
+impl std::fmt::Display for AxisConfig {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match evdev::event_code_name(EV_ABS, self.code) {
+            Some(name) => write!(fmt, "{}:{}", self.output, name),
+            None => write!(fmt, "{}:{}", self.output, self.code),
+        }
+    }
+}
+
+impl std::fmt::Display for Axis {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(fmt)
+    }
+}
+
+pub struct Axis {
+    config: AxisConfig,
+    /// For the negative and the positive key, the order in which they were
+    /// pressed, if they are held.
+    held: [Option<u64>; 2],
+    presses: u64,
+    /// From -1 to 1.
+    position: f32,
+    updated: Instant,
+}
+
+impl Axis {
+    pub fn new(config: AxisConfig) -> Self {
+        Self {
+            config,
+            held: [None, None],
+            presses: 0,
+            position: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn output(&self) -> &DeviceId {
+        &self.config.output
+    }
+
+    pub fn code(&self) -> u32 {
+        self.config.code
+    }
+
+    pub fn position(&self) -> f32 {
+        self.position
+    }
+
+    /// Updates the keys held if `event` concerns one of them. Returns
+    /// whether it does, in which case the event is meant for the axis only.
+    pub fn handle(&mut self, event: &Event, now: Instant) -> bool {
+        let target = event.target();
+        if target.type_() != EV_KEY {
+            return false;
+        }
+
+        let matches = |key: &Option<(Option<DeviceId>, u32)>| match key {
+            Some((device, code)) => *code == target.code()
+                && (device.is_none() || *device == Some(event.srcdev_id())),
+            None => false,
+        };
+        let index = match (matches(&self.config.negative), matches(&self.config.positive)) {
+            (true, _) => 0,
+            (_, true) => 1,
+            _ => return false,
+        };
+
+        match event.value() {
+            0 => self.set_key(index, false, now),
+            1 => self.set_key(index, true, now),
+            _ => {},
+        }
+
+        true
+    }
+
+    /// Presses or releases the negative key, at `index` 0, or the positive
+    /// one, at 1.
+    fn set_key(&mut self, index: usize, pressed: bool, now: Instant) {
+        /* movement up to now follows the keys held so far */
+        self.update(now);
+        self.held[index] = match pressed {
+            true => {
+                self.presses += 1;
+                Some(self.presses)
+            },
+            false => None,
+        };
+    }
+
+    /// Lets go of the held keys for which `held`, given the device each key
+    /// is restricted to and its code, returns `false`.
+    pub fn release_keys<F>(&mut self, now: Instant, held: F)
+        where F: Fn(Option<&DeviceId>, u32) -> bool
+    {
+        self.update(now);
+        let keys = [&self.config.negative, &self.config.positive];
+        for (index, key) in keys.iter().enumerate() {
+            if let Some((device, code)) = key {
+                if !held(device.as_ref(), *code) {
+                    self.held[index] = None;
+                }
+            }
+        }
+    }
+
+    /// Releases both keys and centers the axis.
+    pub fn reset(&mut self) {
+        self.held = [None, None];
+        self.position = 0.0;
+    }
+
+    /// Returns whether the axis is still on its way to where the keys push
+    /// it.
+    pub fn is_ramping(&self) -> bool {
+        self.position != self.target()
+    }
+
+    /// Moves the axis for the time passed since the last update, and returns
+    /// its position. Moving away from the center takes the ramp time, while
+    /// returning to it is immediate.
+    pub fn update(&mut self, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = now;
+
+        /* returning to the center, or turning to the other side, skips the
+         * way back to the center */
+        let target = self.target();
+        if target == 0.0 || target * self.position < 0.0 {
+            self.position = 0.0;
+        }
+
+        let step = match self.config.ramp.as_secs_f32() {
+            ramp if ramp > 0.0 => elapsed.as_secs_f32() / ramp,
+            _ => 1.0,
+        };
+        self.position = match target > self.position {
+            true => (self.position + step).min(target),
+            false => (self.position - step).max(target),
+        };
+
+        self.position
+    }
+
+    fn target(&self) -> f32 {
+        match self.held {
+            [None, None] => 0.0,
+            [Some(_), None] => -1.0,
+            [None, Some(_)] => 1.0,
+            [Some(negative), Some(positive)] => match self.config.socd {
+                Socd::Last if positive > negative => 1.0,
+                Socd::First if positive < negative => 1.0,
+                Socd::Last | Socd::First => -1.0,
+                Socd::Neutral => 0.0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    const RAMP: Duration = Duration::from_millis(100);
+
+    fn axis(ramp: Duration, socd: Socd) -> Axis {
+        Axis::new(AxisConfig {
+            output: Rc::new("pad".to_string()),
+            code: ABS_X,
+            negative: Some((None, KEY_A)),
+            positive: Some((Some(Rc::new("keyboard".to_string())), KEY_D)),
+            ramp,
+            socd,
+        })
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    fn assert_near(position: f32, expected: f32) {
+        assert!((position - expected).abs() < 1e-3, "{} is not {}", position, expected);
+    }
+
+    /// Returns where the axis settles with the negative key pressed, and
+    /// then the positive one.
+    fn both_held(socd: Socd) -> f32 {
+        let mut axis = axis(Duration::from_secs(0), socd);
+        let now = Instant::now();
+        axis.set_key(0, true, now);
+        axis.set_key(1, true, now);
+        axis.update(now)
+    }
+
+    #[test]
+    fn socd() {
+        assert_eq!(both_held(Socd::Last), 1.0);
+        assert_eq!(both_held(Socd::First), -1.0);
+        assert_eq!(both_held(Socd::Neutral), 0.0);
+    }
+
+    #[test]
+    fn socd_after_release() {
+        let mut axis = axis(Duration::from_secs(0), Socd::Neutral);
+        let now = Instant::now();
+        axis.set_key(0, true, now);
+        axis.set_key(1, true, now);
+        axis.set_key(1, false, now);
+        assert_eq!(axis.update(now), -1.0);
+    }
+
+    #[test]
+    fn ramp() {
+        let mut axis = axis(RAMP, Socd::Last);
+        let start = Instant::now();
+        axis.set_key(1, true, start);
+
+        assert_eq!(axis.update(start), 0.0);
+        assert_near(axis.update(ms(start, 25)), 0.25);
+        assert_near(axis.update(ms(start, 50)), 0.5);
+        assert!(axis.is_ramping());
+        assert_eq!(axis.update(ms(start, 100)), 1.0);
+        assert!(!axis.is_ramping());
+        assert_eq!(axis.update(ms(start, 300)), 1.0);
+
+        /* back to the center at once */
+        axis.set_key(1, false, ms(start, 400));
+        assert_eq!(axis.update(ms(start, 400)), 0.0);
+        assert!(!axis.is_ramping());
+    }
+
+    #[test]
+    fn no_ramp() {
+        let mut axis = axis(Duration::from_secs(0), Socd::Last);
+        let now = Instant::now();
+        axis.set_key(0, true, now);
+        assert_eq!(axis.update(now), -1.0);
+    }
+
+    #[test]
+    fn reversal() {
+        let mut axis = axis(RAMP, Socd::Last);
+        let start = Instant::now();
+        axis.set_key(1, true, start);
+        assert_near(axis.update(ms(start, 60)), 0.6);
+
+        /* the other side starts from the center rather than from 0.6 */
+        axis.set_key(0, true, ms(start, 60));
+        assert_eq!(axis.update(ms(start, 60)), 0.0);
+        assert_near(axis.update(ms(start, 90)), -0.3);
+
+        axis.set_key(0, false, ms(start, 90));
+        assert_eq!(axis.update(ms(start, 90)), 0.0);
+        assert_near(axis.update(ms(start, 140)), 0.5);
+    }
+
+    #[test]
+    fn release_keys() {
+        let mut axis = axis(Duration::from_secs(0), Socd::Neutral);
+        let now = Instant::now();
+        axis.set_key(0, true, now);
+        axis.set_key(1, true, now);
+
+        /* the keyboard is gone, the other devices still hold KEY_A */
+        axis.release_keys(now, |device, code| match device {
+            Some(device) => device.as_str() != "keyboard",
+            None => code == KEY_A,
+        });
+        assert_eq!(axis.update(now), -1.0);
+
+        axis.release_keys(now, |_, _| false);
+        assert_eq!(axis.update(now), 0.0);
+    }
+}
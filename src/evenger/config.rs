@@ -0,0 +1,1236 @@
+//! Configuration file parsing and watching.
+//!
+//! The configuration is line-based. `#` starts a comment.
+//!
+//! ```text
+//! device <id> <path> [on-error exit|drop|reopen]
+//! rule [[<name>]] [<device>:]<TARGETS>[=<trigger>] [if <cond>...] => <action>[, <action>...]
+//! escape <KEY>...
+//! pause <KEY>...
+//! gamepad <id>
+//! axis <gamepad>:<ABS> <KEY|-> <KEY|-> [ramp <ms>] [socd last|first|neutral]
+//! stick <device>:<ABS> <REL> <speed> [<step>...]
+//! ```
+//!
+//! A condition is `[!][<device>:]<CODE>` naming a key, LED or switch that
+//! has to be on (or off, with `!`), or `[!]$<name>[=<value>]` testing a
+//! variable set through the control socket for a value, or for being true
+//! if no value is given. Without a device, `!<CODE>` holds if no device has
+//! the code on; with one, the device has to have the code, and it has to be
+//! off. The `layer` request switches layers by setting `$layer`. Conditions
+//! combine into expressions:
+//!
+//! ```text
+//! <cond> & <cond>                    both hold; the `&` can be left out
+//! <cond> | <cond>                    either holds
+//! !<cond>                            does not hold
+//! (<cond>...)                        groups conditions
+//! all(<cond>, ...)                   all of the conditions hold
+//! any(<cond>, ...)                   any of the conditions holds
+//! only(<KEY>, ...)                   the keys are held, and no other
+//!                                    Ctrl, Shift, Alt or Meta key is
+//! ```
+//!
+//! `&` binds tighter than `|`. Commas only separate the arguments of `all`,
+//! `any` and `only`.
+//!
+//! An action is `[on <trigger>] [pre|post] <kind>`, where `<kind>` is one of
+//! `press KEY`, `release KEY`, `tap KEY`, `chord KEY+KEY...`,
+//! `move REL <amount>`, `value <CODE> <step>...`, `keys <KEY|-> <KEY|->
+//! <press> [<release>]` or `drop`. Actions without a phase replace the
+//! event, if the event triggers any.
+//!
+//! Keys that actions press on the press of a key are released along with
+//! that key, even if the rule has stopped matching by then, e.g. because a
+//! variable changed.
+//!
+//! `keys` turns an axis into a pair of keys, the first held while the axis
+//! is pushed towards its minimum and the second towards its maximum.
+//! Positions go from -1 to 1 over the range of the axis; a key is pressed
+//! past `press` and released again below `release`, which defaults to
+//! `press`; both lie above 0 and at most at 1. Keys held otherwise, e.g. on
+//! a keyboard, are left alone. Rules for two axes of a stick give all eight
+//! directions.
+//!
+//! `value` transforms the value of the event and writes the result to a
+//! relative axis, which moves by it, or a key, which is held while it is
+//! non-zero. Injected actions can also set the absolute axes of a gamepad.
+//! The steps apply in order:
+//!
+//! ```text
+//! scale <factor>, div <divisor>   multiply or divide
+//! offset <amount>                 add
+//! clamp <min> <max>               limit to a range
+//! deadzone <radius>               zero values closer to zero than radius
+//! curve <in>:<out> <in>:<out>...  interpolate between points
+//! threshold <limit>               1 past limit, away from zero, 0 otherwise
+//! ```
+//!
+//! A trigger restricts a rule or an action to events with a value: `press`,
+//! `release`, `repeat`, a number, or `any`, the default. `tap` is a release
+//! of a key that was pressed with no other key pressed in between.
+//!
+//! A rule can match several codes of one type at once:
+//!
+//! ```text
+//! <CODE>..<CODE>      a range, e.g. KEY_F1..KEY_F12, counting by the
+//!                     number ending the names, or KEY_1..KEY_0, in code
+//!                     order otherwise
+//! <PREFIX>*           all codes named so, e.g. KEY_* or BTN_*
+//! @<group>            @letters, @digits, @fkeys, @modifiers, @arrows
+//!                     or @keypad
+//! ```
+//!
+//! The code of an action can then be `*`, standing for the matched code, or
+//! a set of as many codes, mapping each matched code to the one at the same
+//! position: `rule @digits=press if KEY_CAPSLOCK => press KEY_F1..KEY_F10`.
+//!
+//! A rule can be named by starting it with `[<name>]`. When the
+//! configuration is reloaded, keys held by a rule are released unless a rule
+//! of the same name, written the same way, is still there; unnamed rules
+//! only need to be written the same way, wherever they are.
+//!
+//! `on-error` decides what happens when a device fails or disappears: evenger
+//! either exits, carries on without the device (the default), or keeps trying
+//! to open it again.
+//!
+//! `escape` sets the keys that, held together on the source devices, make
+//! evenger release everything and exit regardless of the rules. It defaults
+//! to LeftCtrl+RightCtrl+Backspace. `pause` sets keys that, likewise, pause
+//! or resume remapping.
+//!
+//! `gamepad` creates a virtual gamepad, whose axes `axis` lets a pair of keys
+//! push towards their minimum and maximum. The keys then only drive the
+//! axis. The axis moves from the center to either end in `ramp`
+//! milliseconds, 0 by default, and `socd` decides where it goes while both
+//! keys are held: towards the key pressed last, the default, or first, or
+//! back to the center.
+//!
+//! `stick` lets the axis of an analog stick move the pointer or scroll, at
+//! `speed` units per second when pushed all the way. The steps, as for
+//! `value`, apply to the stick position from -1 to 1 first; `deadzone` and
+//! `curve` are the usual ones. The axis then only drives the movement.
+
+use crate::evdev;
+use crate::foreign::*;
+use super::{DeviceId, Error, Result};
+use super::axis::{AxisConfig, Socd};
+use super::destdev::{Action, GAMEPAD_AXES};
+use super::rule::{MODIFIER_KEYS, ActionRule, ActionRulePhase, CodeArg, Condition, ModifierRule, Rule, RuleSet, TargetSet, Trigger};
+use super::srcdev::{EventTarget, Modifier};
+use super::stick::StickConfig;
+use super::transform::{Step, Transform};
+use super::vars;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::ffi::OsString;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// The default escape chord.
+pub const DEFAULT_ESCAPE: &[u32] = &[KEY_LEFTCTRL, KEY_RIGHTCTRL, KEY_BACKSPACE];
+
+pub struct Config {
+    pub devices: Vec<DeviceConfig>,
+    pub rules: RuleSet,
+    /// Keys that make evenger exit when held together.
+    pub escape: Vec<u32>,
+    /// Keys that pause or resume remapping when held together, if any.
+    pub pause: Vec<u32>,
+    /// Virtual gamepads to create.
+    pub gamepads: Vec<DeviceId>,
+    /// Gamepad axes driven by keys.
+    pub axes: Vec<AxisConfig>,
+    /// Analog sticks moving the pointer or scrolling.
+    pub sticks: Vec<StickConfig>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct DeviceConfig {
+    pub id: DeviceId,
+    pub path: PathBuf,
+    pub on_error: ErrorPolicy,
+}
+
+/// What to do with a source device that fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorPolicy {
+    /// Stop evenger.
+    Exit,
+    /// Close the device and carry on without it.
+    #[default]
+    Drop,
+    /// Close the device and try to open it again periodically.
+    Reopen,
+}
+
+/// Watches a configuration file for modifications.
+///
+/// The parent directory is watched rather than the file itself, so that
+/// editors replacing the file by renaming are noticed as well.
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    filename: OsString,
+}
+
+/// A syntax error, along with the part of the line it was found in.
+struct ParseError<'a> {
+    token: &'a str,
+    msg: String,
+}
+
+type ParseResult<'a, T> = std::result::Result<T, ParseError<'a>>;
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Description(
+                format!("can't read {}", path.display()), Box::new(e)))?;
+
+        Self::parse(&text).map_err(|e| match e {
+            Error::Config { line, col, msg, .. } =>
+                Error::Config { path: Some(path.to_path_buf()), line, col, msg },
+            e => Error::Description(path.display().to_string(), Box::new(e)),
+        })
+    }
+
+    /// Parses a configuration. Syntax errors are reported as
+    /// `Error::Config`, locating the offending word.
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config = Config {
+            devices: Vec::new(),
+            rules: RuleSet::new(),
+            escape: DEFAULT_ESCAPE.to_vec(),
+            pause: Vec::new(),
+            gamepads: Vec::new(),
+            axes: Vec::new(),
+            sticks: Vec::new(),
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            config.parse_line(line)
+                .map_err(|e| Error::Config {
+                    path: None,
+                    line: index + 1,
+                    col: e.column(line),
+                    msg: e.msg,
+                })?;
+        }
+
+        config.check_devices(&config.devices)?;
+        config.check_gamepads(&config.gamepads)?;
+        Ok(config)
+    }
+
+    pub fn device(&self, id: &DeviceId) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|dev| dev.id == *id)
+    }
+
+    fn parse_line<'a>(&mut self, line: &'a str) -> ParseResult<'a, ()> {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        }.trim();
+
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => Ok(()),
+            Some("device") => {
+                let words: Vec<&str> = words.collect();
+                let (id, path, policy) = match words.as_slice() {
+                    [id, path] => (*id, *path, None),
+                    [id, path, "on-error", policy] => (*id, *path, Some(*policy)),
+                    _ => return Err(ParseError::new(line,
+                        "expected `device <id> <path> [on-error <policy>]`")),
+                };
+                let device_id = Rc::new(id.to_string());
+                if self.device(&device_id).is_some() {
+                    return Err(ParseError::new(id, format!("duplicate device: {}", id)));
+                }
+                let on_error = match policy {
+                    None => ErrorPolicy::default(),
+                    Some("exit") => ErrorPolicy::Exit,
+                    Some("drop") => ErrorPolicy::Drop,
+                    Some("reopen") => ErrorPolicy::Reopen,
+                    Some(policy) => return Err(ParseError::new(policy,
+                        format!("unknown error policy: {}", policy))),
+                };
+                self.devices.push(DeviceConfig { id: device_id, path: path.into(), on_error });
+                Ok(())
+            },
+            Some("rule") => {
+                let mut text = line["rule".len()..].trim();
+                let name = match text.strip_prefix('[') {
+                    Some(rest) => {
+                        let end = rest.find(']')
+                            .ok_or_else(|| ParseError::new(text, "expected `]` after the rule name"))?;
+                        let name = &rest[..end];
+                        if name.is_empty() || name.contains(char::is_whitespace) {
+                            return Err(ParseError::new(text, "invalid rule name"));
+                        }
+                        if self.rules.get(name).is_some() {
+                            return Err(ParseError::new(name, format!("duplicate rule: {}", name)));
+                        }
+                        text = rest[end + 1..].trim_start();
+                        name.to_string()
+                    },
+                    None => String::new(),
+                };
+                let definition = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                let rule = parse_rule(text)?;
+                self.rules.push(rule.with_name(name).with_definition(definition));
+                Ok(())
+            },
+            Some(directive @ "escape") | Some(directive @ "pause") => {
+                let keys = words.map(|key| parse_code(EV_KEY, key))
+                    .collect::<ParseResult<Vec<_>>>()?;
+                if keys.is_empty() {
+                    return Err(ParseError::new(line,
+                        format!("expected `{} <KEY>...`", directive)));
+                }
+                match directive {
+                    "escape" => self.escape = keys,
+                    _ => self.pause = keys,
+                }
+                Ok(())
+            },
+            Some("gamepad") => {
+                let id = match words.collect::<Vec<_>>().as_slice() {
+                    [id] => *id,
+                    _ => return Err(ParseError::new(line, "expected `gamepad <id>`")),
+                };
+                let gamepad_id = Rc::new(id.to_string());
+                if id == "output" || self.gamepads.contains(&gamepad_id) {
+                    return Err(ParseError::new(id, format!("duplicate output device: {}", id)));
+                }
+                self.gamepads.push(gamepad_id);
+                Ok(())
+            },
+            Some("axis") => {
+                let axis = parse_axis(line["axis".len()..].trim())?;
+                if self.axes.iter().any(|other| other.output == axis.output && other.code == axis.code) {
+                    return Err(ParseError::new(line, "duplicate axis"));
+                }
+                self.axes.push(axis);
+                Ok(())
+            },
+            Some("stick") => {
+                let stick = parse_stick(line["stick".len()..].trim())?;
+                if self.sticks.iter().any(|other| other.device == stick.device && other.code == stick.code) {
+                    return Err(ParseError::new(line, "duplicate stick"));
+                }
+                self.sticks.push(stick);
+                Ok(())
+            },
+            Some(word) => Err(ParseError::new(word, format!("unknown directive: {}", word))),
+        }
+    }
+
+    /// Checks that every device referenced by a rule is in `devices`.
+    pub fn check_devices(&self, devices: &[DeviceConfig]) -> Result<()> {
+        for rule in self.rules.iter() {
+            let ids = rule.device().into_iter()
+                .chain(rule.condition().modifiers().into_iter().filter_map(ModifierRule::device));
+
+            for id in ids {
+                if !devices.iter().any(|dev| dev.id == id) {
+                    return Err(Error::Message(format!(
+                        "rule `{}` refers to unknown device {}", rule.definition(), id)));
+                }
+            }
+        }
+
+        for axis in &self.axes {
+            let ids = axis.negative.iter().chain(&axis.positive)
+                .filter_map(|(device, _)| device.as_ref());
+
+            for id in ids {
+                if !devices.iter().any(|dev| dev.id == *id) {
+                    return Err(Error::Message(format!(
+                        "axis {} refers to unknown device {}", axis, id)));
+                }
+            }
+        }
+
+        for stick in &self.sticks {
+            if !devices.iter().any(|dev| dev.id == stick.device) {
+                return Err(Error::Message(format!(
+                    "stick {} refers to unknown device {}", stick, stick.device)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every gamepad referenced by an axis is in `gamepads`.
+    pub fn check_gamepads(&self, gamepads: &[DeviceId]) -> Result<()> {
+        for axis in &self.axes {
+            if !gamepads.contains(&axis.output) {
+                return Err(Error::Message(format!(
+                    "axis {} refers to unknown gamepad {}", axis, axis.output)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> ParseError<'a> {
+    fn new<S: Into<String>>(token: &'a str, msg: S) -> Self {
+        ParseError { token, msg: msg.into() }
+    }
+
+    /// Returns the column of the token within `line`, counting from 1.
+    fn column(&self, line: &str) -> usize {
+        let offset = (self.token.as_ptr() as usize).wrapping_sub(line.as_ptr() as usize);
+        match line.get(..offset) {
+            Some(before) => before.chars().count() + 1,
+            None => 0,
+        }
+    }
+}
+
+impl ConfigWatcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<ConfigWatcher> {
+        let path = path.as_ref();
+        let filename = path.file_name()
+            .ok_or_else(|| Error::Message(format!("not a file: {}", path.display())))?
+            .to_os_string();
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let watcher = ConfigWatcher { inotify, filename };
+        watcher.inotify.add_watch(dir,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO)?;
+
+        Ok(watcher)
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+
+    /// Drains pending notifications, returning whether any of them concerns
+    /// the configuration file.
+    pub fn changed(&self) -> Result<bool> {
+        let mut changed = false;
+        loop {
+            match self.inotify.read_events() {
+                Ok(events) => changed |= events.iter()
+                    .any(|ev| ev.name.as_ref() == Some(&self.filename)),
+                Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => return Ok(changed),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd());
+    }
+}
+
+fn parse_rule(text: &str) -> ParseResult<'_, Rule> {
+    let (head, body) = match text.find("=>") {
+        Some(i) => (&text[..i], &text[i + 2..]),
+        None => return Err(ParseError::new(text, "expected `=>` in rule")),
+    };
+
+    let head = head.trim();
+    let main_end = head.find(char::is_whitespace).unwrap_or(head.len());
+    let (main, tail) = (&head[..main_end], head[main_end..].trim_start());
+    if main.is_empty() {
+        return Err(ParseError::new(text, "expected an event code"));
+    }
+    let (device, main) = split_device(main);
+    let (main, trigger) = match main.find('=') {
+        Some(i) => (&main[..i], parse_trigger(&main[i + 1..])?),
+        None => (main, Trigger::Any),
+    };
+    let main = parse_target_set(main)?;
+
+    let condition = match tail.split_whitespace().next() {
+        None => Condition::All(Vec::new()),
+        Some("if") => parse_condition(tail["if".len()..].trim_start())?,
+        Some(word) => return Err(ParseError::new(word, format!("expected `if`, found `{}`", word))),
+    };
+
+    let mut rule = Rule::new(device, main, condition).with_trigger(trigger);
+
+    for action in body.split(',') {
+        parse_action(&mut rule, action)?;
+    }
+
+    Ok(rule)
+}
+
+/// Parses `<gamepad>:<ABS> <KEY|-> <KEY|-> [ramp <ms>] [socd <mode>]`.
+fn parse_axis<'a>(text: &'a str) -> ParseResult<'a, AxisConfig> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let (axis, negative, positive, options) = match words.as_slice() {
+        [axis, negative, positive, options @ ..] => (*axis, *negative, *positive, options),
+        _ => return Err(ParseError::new(text,
+            "expected `axis <gamepad>:<ABS> <KEY|-> <KEY|-> [ramp <ms>] [socd <mode>]`")),
+    };
+
+    let (output, code) = match split_device(axis) {
+        (Some(output), code) => (output, parse_code(EV_ABS, code)?),
+        (None, _) => return Err(ParseError::new(axis, "expected `<gamepad>:<ABS>`")),
+    };
+    if !GAMEPAD_AXES.contains(&code) {
+        return Err(ParseError::new(axis, format!("gamepads have no axis {}", axis)));
+    }
+
+    let key = |word: &'a str| match split_device(word) {
+        (None, "-") => Ok(None),
+        (device, key) => parse_code(EV_KEY, key).map(|code| Some((device, code))),
+    };
+    let mut config = AxisConfig {
+        output,
+        code,
+        negative: key(negative)?,
+        positive: key(positive)?,
+        ramp: Duration::from_millis(0),
+        socd: Socd::default(),
+    };
+
+    for option in options.chunks(2) {
+        match option {
+            ["ramp", ms] => config.ramp = Duration::from_millis(ms.parse::<u64>()
+                .map_err(|_| ParseError::new(ms, format!("invalid duration: {}", ms)))?),
+            ["socd", "last"] => config.socd = Socd::Last,
+            ["socd", "first"] => config.socd = Socd::First,
+            ["socd", "neutral"] => config.socd = Socd::Neutral,
+            ["socd", mode] => return Err(ParseError::new(mode, format!("unknown SOCD mode: {}", mode))),
+            [word @ "ramp"] | [word @ "socd"] => return Err(ParseError::new(word,
+                format!("expected a value after `{}`", word))),
+            [word, ..] => return Err(ParseError::new(word, format!("unknown axis option: {}", word))),
+            [] => {},
+        }
+    }
+
+    Ok(config)
+}
+
+/// Parses `<device>:<ABS> <REL> <speed> [<step>...]`.
+fn parse_stick(text: &str) -> ParseResult<'_, StickConfig> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let (stick, output, speed, steps) = match words.as_slice() {
+        [stick, output, speed, steps @ ..] => (*stick, *output, *speed, steps),
+        _ => return Err(ParseError::new(text,
+            "expected `stick <device>:<ABS> <REL> <speed> [<step>...]`")),
+    };
+
+    let (device, code) = match split_device(stick) {
+        (Some(device), code) => (device, parse_code(EV_ABS, code)?),
+        (None, _) => return Err(ParseError::new(stick, "expected `<device>:<ABS>`")),
+    };
+
+    Ok(StickConfig {
+        device,
+        code,
+        output: parse_code(EV_REL, output)?,
+        speed: parse_number(speed)?,
+        transform: parse_transform(steps)?,
+    })
+}
+
+fn parse_condition(text: &str) -> ParseResult<'_, Condition> {
+    let mut parser = ConditionParser {
+        tokens: tokenize_condition(text),
+        pos: 0,
+        end: &text[text.len()..],
+    };
+
+    let condition = parser.parse_or()?;
+    match parser.peek(0) {
+        None => Ok(condition),
+        Some(token) => Err(ParseError::new(token, format!("unexpected `{}`", token))),
+    }
+}
+
+/// Splits a condition into words and the punctuation `(`, `)`, `,`, `|`,
+/// `&` and `!`. A `!` only counts as punctuation at the start of a word.
+fn tokenize_condition(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    for (i, c) in text.char_indices() {
+        let punct = "(),|&".contains(c) || (c == '!' && start.is_none());
+        if c.is_whitespace() || punct {
+            if let Some(start) = start.take() {
+                tokens.push(&text[start..i]);
+            }
+            if punct {
+                tokens.push(&text[i..i + c.len_utf8()]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&text[start..]);
+    }
+
+    tokens
+}
+
+/// Recursive descent parser for condition expressions.
+struct ConditionParser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+    /// The empty end of the text, to point errors at.
+    end: &'a str,
+}
+
+impl<'a> ConditionParser<'a> {
+    fn peek(&self, ahead: usize) -> Option<&'a str> {
+        self.tokens.get(self.pos + ahead).cloned()
+    }
+
+    fn next(&mut self) -> ParseResult<'a, &'a str> {
+        let token = self.peek(0)
+            .ok_or_else(|| ParseError::new(self.end, "expected a condition"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> ParseResult<'a, ()> {
+        match self.peek(0) {
+            Some(token) if token == expected => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(token) => Err(ParseError::new(token,
+                format!("expected `{}`, found `{}`", expected, token))),
+            None => Err(ParseError::new(self.end, format!("expected `{}`", expected))),
+        }
+    }
+
+    fn parse_or(&mut self) -> ParseResult<'a, Condition> {
+        let mut conds = vec![self.parse_and()?];
+        while self.peek(0) == Some("|") {
+            self.pos += 1;
+            conds.push(self.parse_and()?);
+        }
+
+        Ok(match conds.len() {
+            1 => conds.remove(0),
+            _ => Condition::Any(conds),
+        })
+    }
+
+    fn parse_and(&mut self) -> ParseResult<'a, Condition> {
+        let mut conds = vec![self.parse_unary()?];
+        loop {
+            match self.peek(0) {
+                None | Some("|") | Some(")") | Some(",") => break,
+                Some("&") => self.pos += 1,
+                Some(_) => {},
+            }
+            conds.push(self.parse_unary()?);
+        }
+
+        Ok(match conds.len() {
+            1 => conds.remove(0),
+            _ => Condition::All(conds),
+        })
+    }
+
+    fn parse_unary(&mut self) -> ParseResult<'a, Condition> {
+        let token = self.next()?;
+        match token {
+            "!" => match (self.peek(0), self.peek(1)) {
+                (Some(word), next) if !is_punct(word) && next != Some("(") => {
+                    self.pos += 1;
+                    let modf = parse_modifier(word, true)?;
+                    match modf.device() {
+                        /* a key, LED or switch of a device needs the device to have it */
+                        Some(_) => Ok(Condition::Modifier(parse_modifier(word, false)?)),
+                        /* and without one, none may have it on */
+                        None => Ok(Condition::Not(Box::new(Condition::Modifier(modf)))),
+                    }
+                },
+                _ => Ok(Condition::Not(Box::new(self.parse_unary()?))),
+            },
+            "(" => {
+                let cond = self.parse_or()?;
+                self.expect(")")?;
+                Ok(cond)
+            },
+            "all" | "any" | "only" if self.peek(0) == Some("(") => {
+                self.pos += 1;
+                let mut conds = vec![self.parse_or()?];
+                while self.peek(0) == Some(",") {
+                    self.pos += 1;
+                    conds.push(self.parse_or()?);
+                }
+                self.expect(")")?;
+
+                match token {
+                    "all" => Ok(Condition::All(conds)),
+                    "any" => Ok(Condition::Any(conds)),
+                    _ => conds.into_iter()
+                        .map(|cond| match cond {
+                            Condition::Modifier(modf) if matches!(modf.modifier(), Modifier::Key(_, true))
+                                => Ok(modf),
+                            _ => Err(ParseError::new(token, "`only` takes keys")),
+                        })
+                        .collect::<ParseResult<Vec<_>>>()
+                        .map(Condition::Only),
+                }
+            },
+            token if is_punct(token) => Err(ParseError::new(token, format!("unexpected `{}`", token))),
+            word => Ok(Condition::Modifier(parse_modifier(word, true)?)),
+        }
+    }
+}
+
+fn is_punct(token: &str) -> bool {
+    matches!(token, "(" | ")" | "," | "|" | "&" | "!")
+}
+
+/// Parses `[<device>:]<CODE>` or `$<name>[=<value>]`, which has to be on,
+/// or off if `state` is false.
+fn parse_modifier(text: &str, state: bool) -> ParseResult<'_, ModifierRule> {
+    if let Some(var) = text.strip_prefix('$') {
+        let (name, value) = match var.find('=') {
+            Some(i) => (&var[..i], Some(var[i + 1..].to_string())),
+            None => (var, None),
+        };
+        if !vars::is_valid_name(name) {
+            return Err(ParseError::new(text, format!("invalid variable name: {}", name)));
+        }
+        return Ok(ModifierRule::new(None, Modifier::Var(name.to_string(), value, state)));
+    }
+    let (device, name) = split_device(text);
+    let target = parse_target(name)?;
+
+    let modf = match target.type_() {
+        EV_KEY => Modifier::Key(target.code(), state),
+        EV_LED => Modifier::Led(target.code(), state),
+        EV_SW  => Modifier::Switch(target.code(), state),
+        _ => return Err(ParseError::new(name, format!("not a key, LED or switch: {}", name))),
+    };
+
+    Ok(ModifierRule::new(device, modf))
+}
+
+fn parse_action<'a>(rule: &mut Rule, text: &'a str) -> ParseResult<'a, ()> {
+    let mut words = text.split_whitespace().peekable();
+
+    let trigger = match words.peek() {
+        Some(&"on") => {
+            words.next();
+            match words.next() {
+                Some(word) => parse_trigger(word)?,
+                None => return Err(ParseError::new(text.trim(), "expected a trigger after `on`")),
+            }
+        },
+        _ => Trigger::Any,
+    };
+
+    let phase = match words.peek() {
+        Some(&"pre") => ActionRulePhase::PreAction,
+        Some(&"post") => ActionRulePhase::PostAction,
+        _ => ActionRulePhase::PeriAction,
+    };
+    if phase != ActionRulePhase::PeriAction {
+        words.next();
+    }
+
+    let words: Vec<&str> = words.collect();
+    for (action, code) in parse_action_words(&words, text, Some(rule.main()))? {
+        let main = rule.main();
+        match &code {
+            CodeArg::Matched if action.type_() != Some(main.type_()) => {
+                let text = text.trim();
+                return Err(ParseError::new(text,
+                    "`*` needs an action of the same event type as the rule"));
+            },
+            CodeArg::Mapped(codes) if codes.len() != main.len() => {
+                let text = text.trim();
+                return Err(ParseError::new(text, format!(
+                    "expected {} codes to map to, found {}", main.len(), codes.len())));
+            },
+            _ => (),
+        }
+        rule.add_action(ActionRule::new(phase, action)
+            .with_trigger(trigger)
+            .with_code(code));
+    }
+
+    Ok(())
+}
+
+/// Parses comma-separated actions without a phase, e.g.
+/// `chord KEY_LEFTCTRL+KEY_C, move REL_X 10`.
+pub fn parse_actions(text: &str) -> Result<Vec<Action>> {
+    let mut actions = Vec::new();
+    for action in text.split(',') {
+        let words: Vec<&str> = action.split_whitespace().collect();
+        for (action, code) in parse_action_words(&words, action, None)
+                .map_err(|e| Error::Message(e.msg))? {
+            if code != CodeArg::Fixed {
+                return Err(Error::msg("`*` and code sets can only be used in rules"));
+            }
+            actions.push(action);
+        }
+    }
+    Ok(actions)
+}
+
+/// Parses one action into the events it sends, along with where each of
+/// them takes its code from. `main` holds the targets of the rule the action
+/// belongs to, if any.
+fn parse_action_words<'a>(words: &[&'a str], text: &'a str, main: Option<&TargetSet>)
+    -> ParseResult<'a, Vec<(Action, CodeArg)>>
+{
+    let fixed = |action| (action, CodeArg::Fixed);
+
+    Ok(match words {
+        ["press", key] => {
+            let (code, arg) = parse_code_arg(EV_KEY, key)?;
+            vec![(Action::KeyDown(code), arg)]
+        },
+        ["release", key] => {
+            let (code, arg) = parse_code_arg(EV_KEY, key)?;
+            vec![(Action::KeyUp(code), arg)]
+        },
+        ["tap", key] => {
+            let (code, arg) = parse_code_arg(EV_KEY, key)?;
+            vec![(Action::KeyDown(code), arg.clone()), fixed(Action::Sync), (Action::KeyUp(code), arg)]
+        },
+        ["chord", keys] => {
+            let codes = keys.split('+')
+                .map(|key| parse_code(EV_KEY, key))
+                .collect::<ParseResult<Vec<_>>>()?;
+            codes.iter().cloned().map(Action::KeyDown)
+                .chain(Some(Action::Sync))
+                .chain(codes.iter().rev().cloned().map(Action::KeyUp))
+                .map(fixed)
+                .collect()
+        },
+        ["move", rel, amount] => {
+            let (code, arg) = parse_code_arg(EV_REL, rel)?;
+            let amount100 = (parse_number(amount)? * 100.0).round() as i32;
+            vec![(Action::RelativeMove { code, amount100 }, arg)]
+        },
+        ["value", code, steps @ ..] => {
+            let (type_, code, arg) = match (*code, main) {
+                ("*", Some(main)) => (main.type_(), 0, CodeArg::Matched),
+                ("*", None) => return Err(ParseError::new(code, "`*` can only be used in rules")),
+                (code, _) => {
+                    let type_ = parse_target_set(code)?.type_();
+                    let (code, arg) = parse_code_arg(type_, code)?;
+                    (type_, code, arg)
+                },
+            };
+            if ![EV_REL, EV_ABS, EV_KEY].contains(&type_) {
+                return Err(ParseError::new(words[1], format!("can't write values to {}", words[1])));
+            }
+            /* rules write to the main output, which has no absolute axes */
+            if type_ == EV_ABS && main.is_some() {
+                return Err(ParseError::new(words[1], format!("the output has no absolute axes: {}", words[1])));
+            }
+            let transform = parse_transform(steps)?;
+            vec![(Action::Value { type_, code, transform }, arg)]
+        },
+        ["keys", negative, positive, press, release @ ..] if release.len() <= 1 => {
+            let key = |word: &'a str| match word {
+                "-" => Ok(None),
+                word => parse_code(EV_KEY, word).map(Some),
+            };
+            let (negative, positive) = (key(negative)?, key(positive)?);
+            let press = parse_number(press)?;
+            if press <= 0.0 || press > 1.0 {
+                return Err(ParseError::new(words[3], "press threshold not above 0 and at most 1"));
+            }
+            let release = match release.first() {
+                Some(release) => parse_number(release)?,
+                None => press,
+            };
+            if release <= 0.0 {
+                return Err(ParseError::new(words[4], "release threshold not above 0"));
+            }
+            if release > press {
+                return Err(ParseError::new(words[4], "release threshold above press threshold"));
+            }
+            vec![fixed(Action::AxisKeys { negative, positive, press, release })]
+        },
+        ["drop"] => vec![fixed(Action::Drop)],
+        _ => {
+            let text = text.trim();
+            return Err(ParseError::new(text, format!("invalid action: {}", text)));
+        },
+    })
+}
+
+/// Parses the steps of a value transformation, e.g.
+/// `deadzone 8 curve 0:0 64:16 128:128 clamp -100 100`.
+fn parse_transform<'a>(words: &[&'a str]) -> ParseResult<'a, Transform> {
+    let mut steps = Vec::new();
+    let mut words = words.iter().cloned().peekable();
+
+    while let Some(word) = words.next() {
+        let mut number = || match words.next() {
+            Some(arg) => parse_number(arg),
+            None => Err(ParseError::new(word, format!("expected a number after `{}`", word))),
+        };
+
+        let step = match word {
+            "scale" => Step::Scale(number()?),
+            "div" => {
+                let divisor = number()?;
+                if divisor == 0.0 {
+                    return Err(ParseError::new(word, "division by zero"));
+                }
+                Step::Scale(1.0 / divisor)
+            },
+            "offset" => Step::Offset(number()?),
+            "clamp" => {
+                let (min, max) = (number()?, number()?);
+                if min > max {
+                    return Err(ParseError::new(word, "empty range in `clamp`"));
+                }
+                Step::Clamp(min, max)
+            },
+            "deadzone" => Step::DeadZone(number()?),
+            "threshold" => Step::Threshold(number()?),
+            "curve" => {
+                let mut points = Vec::new();
+                while let Some(point) = words.peek().filter(|point| point.contains(':')) {
+                    let i = point.find(':').expect("points contain `:`");
+                    points.push((parse_number(&point[..i])?, parse_number(&point[i + 1..])?));
+                    words.next();
+                }
+                if points.len() < 2 {
+                    return Err(ParseError::new(word, "expected at least two points after `curve`"));
+                }
+                points.sort_by(|a, b| a.0.partial_cmp(&b.0).expect("numbers are not NaN"));
+                Step::Curve(points)
+            },
+            _ => return Err(ParseError::new(word, format!("invalid transformation: {}", word))),
+        };
+        steps.push(step);
+    }
+
+    Ok(Transform::new(steps))
+}
+
+/// Parses the event values a rule or an action reacts to: `press`,
+/// `release`, `repeat`, `tap`, `any` or a number.
+fn parse_trigger(text: &str) -> ParseResult<'_, Trigger> {
+    Ok(match text {
+        "any" => Trigger::Any,
+        "press" => Trigger::Press,
+        "release" => Trigger::Release,
+        "repeat" => Trigger::Repeat,
+        "tap" => Trigger::Tap,
+        _ => Trigger::Value(text.parse::<i32>()
+            .map_err(|_| ParseError::new(text, format!("invalid trigger: {}", text)))?),
+    })
+}
+
+fn split_device(text: &str) -> (Option<DeviceId>, &str) {
+    match text.find(':') {
+        Some(i) => (Some(Rc::new(text[..i].to_string())), &text[i + 1..]),
+        None => (None, text),
+    }
+}
+
+/// Looks up an event code by name, e.g. `KEY_A` or `REL_X`.
+fn parse_target(name: &str) -> ParseResult<'_, EventTarget> {
+    (0..EV_CNT)
+        .find_map(|type_| evdev::event_code_from_name(type_, name)
+            .map(|code| EventTarget::new(type_, code)))
+        .ok_or_else(|| ParseError::new(name, format!("unknown event code: {}", name)))
+}
+
+/// Parses the targets of a rule: a single code, a range, a wildcard or a
+/// group. The codes are kept in order, for actions to map them by position.
+fn parse_target_set(text: &str) -> ParseResult<'_, TargetSet> {
+    if let Some(group) = text.strip_prefix('@') {
+        let codes = group_codes(group)
+            .ok_or_else(|| ParseError::new(text, format!("unknown group: {}", text)))?;
+        return Ok(TargetSet::new(EV_KEY, codes));
+    }
+
+    if let Some(prefix) = text.strip_suffix('*') {
+        let targets = (0..EV_CNT)
+            .flat_map(|type_| (0..=evdev::event_type_max(type_).unwrap_or(0))
+                .map(move |code| EventTarget::new(type_, code)))
+            .filter(|target| !prefix.is_empty() && evdev::event_code_name(target.type_(), target.code())
+                .is_some_and(|name| name.starts_with(prefix)))
+            .collect::<Vec<_>>();
+        let type_ = match targets.first() {
+            Some(target) => target.type_(),
+            None => return Err(ParseError::new(text, format!("no event code matches {}", text))),
+        };
+        if targets.iter().any(|target| target.type_() != type_) {
+            return Err(ParseError::new(text, format!("mixed event types in wildcard: {}", text)));
+        }
+        return Ok(TargetSet::new(type_, targets.iter().map(EventTarget::code).collect()));
+    }
+
+    if let Some(i) = text.find("..") {
+        let (from, to) = (&text[..i], &text[i + 2..]);
+        let first = parse_target(from)?;
+        let last = parse_target(to)?;
+        if first.type_() != last.type_() {
+            return Err(ParseError::new(text, format!("mixed event types in range: {}", text)));
+        }
+        if let Some(codes) = numbered_range(first.type_(), from, to) {
+            return Ok(TargetSet::new(first.type_(), codes));
+        }
+        if first.code() > last.code() {
+            return Err(ParseError::new(text, format!("empty range: {}", text)));
+        }
+        return Ok(TargetSet::new(first.type_(), (first.code()..=last.code()).collect()));
+    }
+
+    parse_target(text).map(TargetSet::single)
+}
+
+/// Expands a range between names that only differ by a number, like
+/// `KEY_F1..KEY_F12`, by name rather than by code, since such codes are not
+/// necessarily contiguous.
+fn numbered_range(type_: u32, from: &str, to: &str) -> Option<Vec<u32>> {
+    fn split(name: &str) -> Option<(&str, u32)> {
+        let digits = name.len() - name.bytes().rev().take_while(u8::is_ascii_digit).count();
+        Some((&name[..digits], name[digits..].parse().ok()?))
+    }
+    let (prefix, first) = split(from)?;
+    let (other, last) = split(to)?;
+    if prefix != other || first > last {
+        return None;
+    }
+
+    (first..=last)
+        .map(|n| evdev::event_code_from_name(type_, &format!("{}{}", prefix, n)))
+        .collect()
+}
+
+/// Returns the keys of a named group.
+fn group_codes(name: &str) -> Option<Vec<u32>> {
+    Some(match name {
+        "letters" => vec![
+            KEY_A, KEY_B, KEY_C, KEY_D, KEY_E, KEY_F, KEY_G, KEY_H, KEY_I,
+            KEY_J, KEY_K, KEY_L, KEY_M, KEY_N, KEY_O, KEY_P, KEY_Q, KEY_R,
+            KEY_S, KEY_T, KEY_U, KEY_V, KEY_W, KEY_X, KEY_Y, KEY_Z,
+        ],
+        "digits" => (KEY_1..=KEY_0).collect(),
+        "fkeys" => vec![
+            KEY_F1, KEY_F2, KEY_F3, KEY_F4, KEY_F5, KEY_F6,
+            KEY_F7, KEY_F8, KEY_F9, KEY_F10, KEY_F11, KEY_F12,
+        ],
+        "modifiers" => MODIFIER_KEYS.to_vec(),
+        "arrows" => vec![KEY_UP, KEY_DOWN, KEY_LEFT, KEY_RIGHT],
+        "keypad" => vec![
+            KEY_KP0, KEY_KP1, KEY_KP2, KEY_KP3, KEY_KP4,
+            KEY_KP5, KEY_KP6, KEY_KP7, KEY_KP8, KEY_KP9,
+            KEY_KPDOT, KEY_KPENTER, KEY_KPPLUS, KEY_KPMINUS,
+            KEY_KPASTERISK, KEY_KPSLASH,
+        ],
+        _ => return None,
+    })
+}
+
+/// Parses the code an action applies to: a single code, `*` for the matched
+/// code, or a set of codes to map the matched ones to.
+fn parse_code_arg<'a>(type_: u32, text: &'a str) -> ParseResult<'a, (u32, CodeArg)> {
+    if text == "*" {
+        return Ok((0, CodeArg::Matched));
+    }
+
+    let set = parse_target_set(text)?;
+    if set.type_() != type_ {
+        return Err(ParseError::new(text, format!("unexpected event code: {}", text)));
+    }
+    match set.len() {
+        1 => Ok((set.targets().next().expect("set has one target").code(), CodeArg::Fixed)),
+        _ => Ok((0, CodeArg::Mapped(set.targets().map(|target| target.code()).collect()))),
+    }
+}
+
+fn parse_code<'a>(type_: u32, name: &'a str) -> ParseResult<'a, u32> {
+    let target = parse_target(name)?;
+    if target.type_() != type_ {
+        return Err(ParseError::new(name, format!("unexpected event code: {}", name)));
+    }
+    Ok(target.code())
+}
+
+/// Parses a finite number; NaN and infinities would slip past range checks.
+fn parse_number(text: &str) -> ParseResult<'_, f32> {
+    match text.parse::<f32>() {
+        Ok(number) if number.is_finite() => Ok(number),
+        _ => Err(ParseError::new(text, format!("invalid number: {}", text))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: u32) -> Condition {
+        Condition::Modifier(ModifierRule::new(None, Modifier::Key(code, true)))
+    }
+
+    fn condition(text: &str) -> Condition {
+        match parse_condition(text) {
+            Ok(condition) => condition,
+            Err(e) => panic!("{}: {}", text, e.msg),
+        }
+    }
+
+    /// Returns the column and message of the error in `text`.
+    fn condition_error(text: &str) -> (usize, String) {
+        match parse_condition(text) {
+            Ok(_) => panic!("{}: parsed", text),
+            Err(e) => (e.column(text), e.msg),
+        }
+    }
+
+    fn codes(text: &str) -> (u32, Vec<u32>) {
+        match parse_target_set(text) {
+            Ok(set) => (set.type_(), set.targets().map(|target| target.code()).collect()),
+            Err(e) => panic!("{}: {}", text, e.msg),
+        }
+    }
+
+    fn target_set_error(text: &str) -> String {
+        match parse_target_set(text) {
+            Ok(_) => panic!("{}: parsed", text),
+            Err(e) => e.msg,
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(condition("KEY_A | KEY_B & KEY_C")
+            == Condition::Any(vec![key(KEY_A), Condition::All(vec![key(KEY_B), key(KEY_C)])]));
+        assert!(condition("KEY_A KEY_B | KEY_C")
+            == Condition::Any(vec![Condition::All(vec![key(KEY_A), key(KEY_B)]), key(KEY_C)]));
+        assert!(condition("(KEY_A | KEY_B) KEY_C")
+            == Condition::All(vec![Condition::Any(vec![key(KEY_A), key(KEY_B)]), key(KEY_C)]));
+    }
+
+    #[test]
+    fn negation() {
+        assert!(condition("!KEY_A") == Condition::Not(Box::new(key(KEY_A))));
+        assert!(condition("!(KEY_A)") == Condition::Not(Box::new(key(KEY_A))));
+        assert!(condition("!KEY_A | KEY_B")
+            == Condition::Any(vec![Condition::Not(Box::new(key(KEY_A))), key(KEY_B)]));
+        assert!(condition("KEY_LEFTSHIFT & !KEY_LEFTCTRL")
+            == Condition::All(vec![key(KEY_LEFTSHIFT), Condition::Not(Box::new(key(KEY_LEFTCTRL)))]));
+
+        let kbd = Some(Rc::new("kbd".to_string()));
+        assert!(condition("!kbd:KEY_A")
+            == Condition::Modifier(ModifierRule::new(kbd, Modifier::Key(KEY_A, false))));
+    }
+
+    #[test]
+    fn functions() {
+        assert!(condition("all(KEY_A, KEY_B | KEY_C)")
+            == Condition::All(vec![key(KEY_A), Condition::Any(vec![key(KEY_B), key(KEY_C)])]));
+        assert!(condition("any(KEY_A,KEY_B)") == Condition::Any(vec![key(KEY_A), key(KEY_B)]));
+        assert!(condition("only(KEY_LEFTCTRL)")
+            == Condition::Only(vec![ModifierRule::new(None, Modifier::Key(KEY_LEFTCTRL, true))]));
+        assert_eq!(condition_error("only(LED_CAPSL)"), (1, "`only` takes keys".to_string()));
+    }
+
+    #[test]
+    fn malformed_conditions() {
+        assert_eq!(condition_error("KEY_A, KEY_B"), (6, "unexpected `,`".to_string()));
+        assert_eq!(condition_error("(KEY_A"), (7, "expected `)`".to_string()));
+        assert_eq!(condition_error("KEY_A &"), (8, "expected a condition".to_string()));
+        assert_eq!(condition_error("KEY_A | )"), (9, "unexpected `)`".to_string()));
+        assert_eq!(condition_error("all(KEY_A KEY_B"), (16, "expected `)`".to_string()));
+        assert_eq!(condition_error("KEY_A | KEY_NOPE"),
+            (9, "unknown event code: KEY_NOPE".to_string()));
+        assert_eq!(condition_error("KEY_A $"), (7, "invalid variable name: ".to_string()));
+    }
+
+    #[test]
+    fn errors_locate_the_word_in_the_line() {
+        let error = |text| match Config::parse(text) {
+            Err(Error::Config { line, col, msg, .. }) => (line, col, msg),
+            Err(e) => panic!("{}: {}", text, e),
+            Ok(_) => panic!("{}: parsed", text),
+        };
+
+        assert_eq!(error("rule KEY_A if KEY_B | => drop"),
+            (1, 22, "expected a condition".to_string()));
+        assert_eq!(error("\nrule [x]  KEY_A if KEY_B, KEY_C => drop"),
+            (2, 25, "unexpected `,`".to_string()));
+        assert_eq!(error("rule KEY_A unless KEY_B => drop"),
+            (1, 12, "expected `if`, found `unless`".to_string()));
+    }
+
+    #[test]
+    fn numbers_are_finite() {
+        for (text, number) in &[
+            ("rule REL_X => value REL_Y curve nan:0 1:1", "nan"),
+            ("rule REL_X => value REL_Y clamp NaN 1", "NaN"),
+            ("rule REL_X => value REL_Y scale inf", "inf"),
+            ("rule REL_X => move REL_Y -infinity", "-infinity"),
+        ] {
+            match Config::parse(text) {
+                Err(Error::Config { msg, .. }) => assert_eq!(msg, format!("invalid number: {}", number)),
+                _ => panic!("{}: expected an invalid number", text),
+            }
+        }
+    }
+
+    #[test]
+    fn key_thresholds() {
+        let error = |text| match Config::parse(text) {
+            Err(Error::Config { msg, .. }) => msg,
+            _ => panic!("{}: expected an error", text),
+        };
+
+        assert!(Config::parse("rule ABS_X => keys KEY_LEFT KEY_RIGHT 1 0.5").is_ok());
+        assert!(Config::parse("rule ABS_X => keys - KEY_RIGHT 0.5").is_ok());
+        assert_eq!(error("rule ABS_X => keys KEY_LEFT KEY_RIGHT 0"),
+            "press threshold not above 0 and at most 1");
+        assert_eq!(error("rule ABS_X => keys KEY_LEFT KEY_RIGHT -0.5 -0.6"),
+            "press threshold not above 0 and at most 1");
+        assert_eq!(error("rule ABS_X => keys KEY_LEFT KEY_RIGHT 1.5"),
+            "press threshold not above 0 and at most 1");
+        assert_eq!(error("rule ABS_X => keys KEY_LEFT KEY_RIGHT 0.5 0"),
+            "release threshold not above 0");
+        assert_eq!(error("rule ABS_X => keys KEY_LEFT KEY_RIGHT 0.5 0.6"),
+            "release threshold above press threshold");
+    }
+
+    #[test]
+    fn target_sets() {
+        assert_eq!(codes("KEY_A"), (EV_KEY, vec![KEY_A]));
+        assert_eq!(codes("KEY_F9..KEY_F12"), (EV_KEY, vec![KEY_F9, KEY_F10, KEY_F11, KEY_F12]));
+        assert_eq!(codes("KEY_Q..KEY_E"), (EV_KEY, vec![KEY_Q, KEY_W, KEY_E]));
+        assert_eq!(codes("@arrows"), (EV_KEY, vec![KEY_UP, KEY_DOWN, KEY_LEFT, KEY_RIGHT]));
+        assert_eq!(codes("@digits").1.len(), 10);
+
+        let (type_, buttons) = codes("BTN_TRIGGER_HAPPY*");
+        assert_eq!(type_, EV_KEY);
+        assert_eq!(buttons.len(), 40);
+        assert_eq!(buttons[0], BTN_TRIGGER_HAPPY1);
+    }
+
+    #[test]
+    fn malformed_target_sets() {
+        assert_eq!(target_set_error("KEY_NOPE"), "unknown event code: KEY_NOPE");
+        assert_eq!(target_set_error("@nope"), "unknown group: @nope");
+        assert_eq!(target_set_error("*"), "no event code matches *");
+        assert_eq!(target_set_error("KEY_A..LED_CAPSL"), "mixed event types in range: KEY_A..LED_CAPSL");
+        assert_eq!(target_set_error("KEY_E..KEY_Q"), "empty range: KEY_E..KEY_Q");
+        assert_eq!(target_set_error("KEY_A.."), "unknown event code: ");
+    }
+}
@@ -0,0 +1,337 @@
+//! Control socket.
+//!
+//! Clients connect to a Unix stream socket and send requests as JSON
+//! objects, one per line. Each request names a `command`, and is answered
+//! with a single line holding a JSON object whose `ok` member tells whether
+//! the request succeeded; failed requests carry an `error` message.
+//!
+//! ```text
+//! > {"command": "status"}
+//! < {"ok": true, "devices": [...], ...}
+//! ```
+//!
+//! Commands are `status`, `pause`, `resume`, `set` to set a variable,
+//! `layer` to switch layers, `inject` and `reload`.
+//!
+//! Since requests can inject input, the socket is only accessible to the
+//! user evenger runs as.
+
+use super::{Error, Result};
+use nix::sys::stat::{umask, Mode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_SOCKET: &str = "/run/evenger.sock";
+
+/// Longest request accepted, to keep misbehaving clients in check.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// Most response data kept for a client that doesn't read it, likewise.
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: HashMap<RawFd, Client>,
+}
+
+struct Client {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    /// Responses not written yet, because the client doesn't keep up.
+    output: Vec<u8>,
+}
+
+/// What became of reading from one of the server's file descriptors.
+pub enum ControlInput {
+    /// Requests received from a client, in order.
+    Requests(Vec<Result<Value>>),
+    /// A new client connected; its file descriptor needs to be watched.
+    Connected(RawFd),
+    Nothing,
+}
+
+impl ControlServer {
+    /// Listens on `path`. A stale socket left behind by a previous instance
+    /// is replaced, but not one that is still in use, nor anything other
+    /// than a socket.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<ControlServer> {
+        let path = path.as_ref();
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(Error::Message(format!(
+                    "{} exists and is not a socket", path.display())));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::Message(format!(
+                    "{} is in use, is evenger already running?", path.display())));
+            }
+            std::fs::remove_file(path)
+                .map_err(|e| Error::Description(
+                    format!("can't remove {}", path.display()), Box::new(e)))?;
+        }
+
+        /* owner-only from the start, rather than chmod after binding */
+        let mask = umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(path);
+        umask(mask);
+        let listener = listener
+            .map_err(|e| Error::Description(
+                format!("can't listen on {}", path.display()), Box::new(e)))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+            listener,
+            clients: HashMap::new(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Returns whether `fd` is the listener or one of the clients.
+    pub fn owns(&self, fd: RawFd) -> bool {
+        fd == self.fd() || self.clients.contains_key(&fd)
+    }
+
+    /// Accepts a client or reads requests, depending on which `fd` became
+    /// readable. Clients that hang up or misbehave are dropped.
+    pub fn read(&mut self, fd: RawFd) -> ControlInput {
+        if fd == self.fd() {
+            return self.accept();
+        }
+
+        let client = match self.clients.get_mut(&fd) {
+            Some(client) => client,
+            None => return ControlInput::Nothing,
+        };
+
+        match client.read() {
+            Ok(requests) => ControlInput::Requests(requests),
+            Err(e) => {
+                if let Some(e) = e {
+                    debug!("control client {}: {}", fd, e);
+                }
+                self.clients.remove(&fd);
+                ControlInput::Nothing
+            },
+        }
+    }
+
+    /// Sends a response to a client. What the client isn't ready to receive
+    /// yet is kept until `flush`.
+    pub fn respond(&mut self, fd: RawFd, response: &Value) {
+        if let Some(client) = self.clients.get_mut(&fd) {
+            let mut line = response.to_string();
+            line.push('\n');
+            client.output.extend_from_slice(line.as_bytes());
+        }
+        self.flush(fd);
+    }
+
+    /// Writes as much of the pending responses to a client as it accepts.
+    pub fn flush(&mut self, fd: RawFd) {
+        let client = match self.clients.get_mut(&fd) {
+            Some(client) => client,
+            None => return,
+        };
+
+        if let Err(e) = client.write() {
+            debug!("control client {}: {}", fd, e);
+            self.clients.remove(&fd);
+        }
+    }
+
+    /// Returns whether responses to a client are waiting for it to become
+    /// writable, or `None` if the client is gone.
+    pub fn has_output(&self, fd: RawFd) -> Option<bool> {
+        self.clients.get(&fd).map(|client| !client.output.is_empty())
+    }
+
+    fn accept(&mut self) -> ControlInput {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                if e.kind() != ErrorKind::WouldBlock {
+                    warn!("can't accept control client: {}", e);
+                }
+                return ControlInput::Nothing;
+            },
+        };
+
+        if let Err(e) = stream.set_nonblocking(true) {
+            warn!("can't accept control client: {}", e);
+            return ControlInput::Nothing;
+        }
+
+        let fd = stream.as_raw_fd();
+        self.clients.insert(fd, Client { stream, buffer: Vec::new(), output: Vec::new() });
+        ControlInput::Connected(fd)
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Client {
+    /// Reads what is available and returns the complete requests. Fails with
+    /// `None` when the client hung up.
+    fn read(&mut self) -> std::result::Result<Vec<Result<Value>>, Option<Error>> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(None),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Some(e.into())),
+            }
+        }
+
+        let mut requests = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            requests.push(serde_json::from_slice(&line)
+                .map_err(|e| Error::Description("invalid request".into(), Box::new(e))));
+        }
+
+        if self.buffer.len() > MAX_REQUEST_LEN {
+            return Err(Some(Error::msg("request too long")));
+        }
+
+        Ok(requests)
+    }
+
+    /// Writes pending output until the client would block.
+    fn write(&mut self) -> Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(Error::msg("connection closed")),
+                Ok(len) => {
+                    self.output.drain(..len);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if self.output.len() > MAX_PENDING_OUTPUT {
+            return Err(Error::msg("too many unread responses"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends a request to a running evenger and returns the response, for
+/// `evenger ctl`.
+pub fn request<P: AsRef<Path>>(path: P, request: &Value) -> Result<Value> {
+    let path = path.as_ref();
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| Error::Description(
+            format!("can't connect to {}", path.display()), Box::new(e)))?;
+
+    let mut line = request.to_string();
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    if response.is_empty() {
+        return Err(Error::msg("connection closed without a response"));
+    }
+
+    serde_json::from_str(&response)
+        .map_err(|e| Error::Description("invalid response".into(), Box::new(e)))
+}
+
+/// Builds a successful response, adding `data` if it is an object.
+pub fn success(data: Value) -> Value {
+    let mut response = json!({ "ok": true });
+    if let (Some(response), Value::Object(data)) = (response.as_object_mut(), data) {
+        response.extend(data);
+    }
+    response
+}
+
+pub fn failure<E: std::fmt::Display>(error: E) -> Value {
+    json!({ "ok": false, "error": error.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("evenger-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn bind_refuses_other_files() {
+        let path = temp_path("not-a-socket");
+        std::fs::write(&path, "keep me").unwrap();
+
+        assert!(ControlServer::bind(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_replaces_stale_sockets() {
+        let path = temp_path("stale.sock");
+        drop(UnixListener::bind(&path).unwrap());
+
+        let server = ControlServer::bind(&path).unwrap();
+        assert!(ControlServer::bind(&path).is_err());
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn requests_and_responses() {
+        let path = temp_path("requests.sock");
+        let mut server = ControlServer::bind(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+
+        let fd = match server.read(server.fd()) {
+            ControlInput::Connected(fd) => fd,
+            _ => panic!("expected a client"),
+        };
+
+        client.write_all(b"{\"command\": \"status\"}\n\nnot json\n{\"comm").unwrap();
+        let requests = match server.read(fd) {
+            ControlInput::Requests(requests) => requests,
+            _ => panic!("expected requests"),
+        };
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].as_ref().unwrap()["command"], "status");
+        assert!(requests[1].is_err());
+
+        server.respond(fd, &success(json!({ "rules": 3 })));
+        server.respond(fd, &failure("no"));
+        assert_eq!(server.has_output(fd), Some(false));
+
+        let mut lines = BufReader::new(client).lines();
+        let response: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(response, json!({ "ok": true, "rules": 3 }));
+        let response: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(response, json!({ "ok": false, "error": "no" }));
+    }
+}
@@ -1,14 +1,52 @@
 
-use crate::evdev::{Device, UInputDevice};
+use crate::evdev::{AbsInfo, Device, InputId, UInputDevice};
 use crate::foreign::*;
 use super::{DeviceId, Error, Result};
-use std::cell::Cell;
+use super::srcdev::EventTarget;
+use super::transform::Transform;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// Prefix of the names of evenger's virtual devices.
+const NAME_PREFIX: &str = "evenger ";
+/// `BUS_VIRTUAL` from `linux/input.h`.
+const BUS_VIRTUAL: u16 = 0x06;
+/// The node uinput devices are created through.
+const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Axes of virtual gamepads, all ranging over `-GAMEPAD_AXIS_MAX..=GAMEPAD_AXIS_MAX`.
+pub const GAMEPAD_AXES: &[u32] = &[ABS_X, ABS_Y, ABS_Z, ABS_RX, ABS_RY, ABS_RZ];
+pub const GAMEPAD_AXIS_MAX: i32 = 32767;
+/// Buttons of virtual gamepads, which also make them recognized as such.
+const GAMEPAD_BUTTONS: &[u32] = &[
+    BTN_SOUTH, BTN_EAST, BTN_NORTH, BTN_WEST, BTN_TL, BTN_TR, BTN_TL2, BTN_TR2,
+    BTN_SELECT, BTN_START, BTN_MODE, BTN_THUMBL, BTN_THUMBR,
+    BTN_DPAD_UP, BTN_DPAD_DOWN, BTN_DPAD_LEFT, BTN_DPAD_RIGHT,
+];
 
 pub struct DestinationDevice {
     id: DeviceId,
-    uidev: UInputDevice,
+    sink: Sink,
     components: InternalComponents,
     should_sync: Cell<bool>,
+    capture: RefCell<Option<Vec<(EventTarget, i32)>>>,
+}
+
+/// Where the events written to a `DestinationDevice` end up.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SinkKind {
+    /// A virtual device created through uinput.
+    UInput,
+    /// The log, for trying out configurations without touching real input.
+    Log,
+    /// Nowhere. Events are still visible through the capture buffer.
+    Discard,
+}
+
+enum Sink {
+    UInput(UInputDevice),
+    Log,
+    Discard,
 }
 
 #[derive(Clone)]
@@ -19,12 +57,44 @@ pub enum Action {
     },
     KeyUp(u32),
     KeyDown(u32),
+    /// Writes the value of the triggering event, transformed, to `code`: a
+    /// relative axis moves by it, an absolute axis is set to it, and a key
+    /// is held while it is non-zero.
+    Value {
+        type_: u32,
+        code: u32,
+        transform: Transform,
+    },
+    /// Holds `negative` or `positive` while the position of the triggering
+    /// axis is beyond `press` in their direction, until it falls back below
+    /// `release`. Non-axis events count with their value as position. Keys
+    /// held for other devices are neither pressed nor released.
+    AxisKeys {
+        negative: Option<u32>,
+        positive: Option<u32>,
+        press: f32,
+        release: f32,
+    },
+    /// Ends the output frame with `SYN_REPORT`, so that the press and the
+    /// release of a synthesized tap don't arrive together.
+    Sync,
+    /// Does nothing. Used as a peri-action to discard an event.
+    Drop,
+}
+
+/// The event an action is performed for.
+#[derive(Clone, Copy, Default)]
+pub struct ActionInput {
+    pub value: i32,
+    /// For absolute axes, the value scaled to -1..1 over the axis range.
+    pub position: Option<f32>,
 }
 
 #[derive(Default)]
 struct InternalComponents {
     relative: Option<Vec<Cell<RelativeComponent>>>,
-    key: Option<Vec<Cell<KeyComponent>>>,
+    absolute: Option<Vec<Cell<AbsoluteComponent>>>,
+    key: Option<Vec<RefCell<KeyComponent>>>,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -33,8 +103,39 @@ struct RelativeComponent {
 }
 
 #[derive(Clone, Copy, Default)]
+struct AbsoluteComponent {
+    value: i32,
+}
+
+#[derive(Clone, Default)]
 struct KeyComponent {
     pressed: bool,
+    /// Source device whose event caused the key press, if any.
+    owner: Option<DeviceId>,
+}
+
+impl Action {
+    /// Returns the event type the action writes, if any.
+    pub fn type_(&self) -> Option<u32> {
+        match self {
+            Action::RelativeMove{..} => Some(EV_REL),
+            Action::KeyUp(_) | Action::KeyDown(_) | Action::AxisKeys{..} => Some(EV_KEY),
+            Action::Value{type_, ..} => Some(*type_),
+            Action::Sync | Action::Drop => None,
+        }
+    }
+
+    /// Returns the same action applied to `code` instead.
+    pub fn with_code(&self, code: u32) -> Action {
+        match *self {
+            Action::RelativeMove{amount100, ..} => Action::RelativeMove{code, amount100},
+            Action::KeyUp(_) => Action::KeyUp(code),
+            Action::KeyDown(_) => Action::KeyDown(code),
+            Action::Value{type_, ref transform, ..}
+                => Action::Value{type_, code, transform: transform.clone()},
+            Action::AxisKeys{..} | Action::Sync | Action::Drop => self.clone(),
+        }
+    }
 }
 
 // TODO: implement device capability
@@ -42,10 +143,10 @@ struct KeyComponent {
 // }
 
 impl DestinationDevice {
-    pub fn new(id: DeviceId) -> Result<DestinationDevice> {
-        let mut dev = Device::new().unwrap();
-
-        dev.set_name("evdev device");
+    /// Creates the output device. A virtual device is only created for
+    /// `SinkKind::UInput`.
+    pub fn new(id: DeviceId, sink: SinkKind) -> Result<DestinationDevice> {
+        let mut dev = Self::blank_device(&id);
 
         {
             dev.enable_event(EV_REL, REL_X);
@@ -69,17 +170,68 @@ impl DestinationDevice {
             }
         }
 
-        let uidev = UInputDevice::new_from_device(dev)?;
-
         let mut components = InternalComponents::default();
         components.relative = Some(vec![Default::default(); REL_CNT as usize]);
         components.key = Some(vec![Default::default(); KEY_CNT as usize]);
 
+        Self::create(id, sink, dev, components)
+    }
+
+    /// Creates a virtual gamepad with the axes of `GAMEPAD_AXES`, centered,
+    /// and the buttons of `GAMEPAD_BUTTONS`.
+    pub fn gamepad(id: DeviceId, sink: SinkKind) -> Result<DestinationDevice> {
+        let mut dev = Self::blank_device(&id);
+
+        let info = AbsInfo {
+            minimum: -GAMEPAD_AXIS_MAX,
+            maximum: GAMEPAD_AXIS_MAX,
+            ..Default::default()
+        };
+        for &code in GAMEPAD_AXES {
+            dev.enable_abs_event(code, &info)
+                .map_err(|errno| Error::device(&id, UINPUT_PATH, errno))?;
+        }
+        for &code in GAMEPAD_BUTTONS {
+            dev.enable_event(EV_KEY, code);
+        }
+
+        let components = InternalComponents {
+            absolute: Some(vec![Default::default(); ABS_CNT as usize]),
+            key: Some(vec![Default::default(); KEY_CNT as usize]),
+            ..Default::default()
+        };
+
+        Self::create(id, sink, dev, components)
+    }
+
+    fn blank_device(id: &DeviceId) -> Device {
+        let mut dev = Device::new().unwrap();
+
+        dev.set_name(format!("{}{}", NAME_PREFIX, id));
+        dev.set_id(InputId {
+            bustype: BUS_VIRTUAL,
+            ..Default::default()
+        });
+
+        dev
+    }
+
+    fn create(id: DeviceId, sink: SinkKind, dev: Device, components: InternalComponents)
+        -> Result<DestinationDevice>
+    {
+        let sink = match sink {
+            SinkKind::UInput => Sink::UInput(UInputDevice::new_from_device(dev)
+                .map_err(|errno| Error::device(&id, UINPUT_PATH, errno))?),
+            SinkKind::Log => Sink::Log,
+            SinkKind::Discard => Sink::Discard,
+        };
+
         Ok(DestinationDevice {
             id,
-            uidev,
+            sink,
             components, 
             should_sync: Cell::from(false),
+            capture: RefCell::new(None),
         })
     }
 
@@ -89,15 +241,94 @@ impl DestinationDevice {
 
     pub fn write_event(&self, type_: u32, code: u32, value: i32) -> Result<()> {
         self.should_sync.set(true);
-        Ok(self.uidev.write_event(type_, code, value)?)
+
+        if let Some(capture) = self.capture.borrow_mut().as_mut() {
+            capture.push((EventTarget::new(type_, code), value));
+        }
+
+        match &self.sink {
+            Sink::UInput(uidev) => Ok(uidev.write_event(type_, code, value)?),
+            Sink::Log => {
+                info!("{}: {} {}", self.id, EventTarget::new(type_, code), value);
+                Ok(())
+            },
+            Sink::Discard => Ok(()),
+        }
     }
 
-    pub fn perform_action(&self, action: Action) -> Result<()> {
-        match action {
+    /// Starts recording every event written to this device.
+    pub fn start_capture(&self) {
+        *self.capture.borrow_mut() = Some(Vec::new());
+    }
+
+    /// Stops recording and returns the events written since `start_capture`.
+    pub fn take_capture(&self) -> Vec<(EventTarget, i32)> {
+        self.capture.borrow_mut().take().unwrap_or_default()
+    }
+
+    /// Passes an event from `source` through. Key events are tracked, so
+    /// that they can be released along with the source.
+    pub fn forward_event(&self, source: &DeviceId, type_: u32, code: u32, value: i32) -> Result<()> {
+        if type_ == EV_KEY {
+            let mut component = self.key_component(code)?.borrow_mut();
+            match value {
+                0 => {
+                    component.pressed = false;
+                    component.owner = None;
+                },
+                1 => {
+                    component.pressed = true;
+                    component.owner = Some(Rc::clone(source));
+                },
+                _ => {},
+            }
+        }
+
+        self.write_event(type_, code, value)
+    }
+
+    /// Performs `action`. `input` describes the event that triggered the
+    /// action, and `source` the device it came from.
+    pub fn perform_action(&self, action: &Action, input: ActionInput, source: Option<&DeviceId>) -> Result<()> {
+        match *action {
+            Action::Value{type_, code, ref transform} => {
+                let value = transform.apply(input.value as f32);
+                match type_ {
+                    EV_REL => self.move_relative(code, value),
+                    EV_ABS => self.set_absolute(code, value.round() as i32),
+                    EV_KEY => self.press_key_from(code, value != 0.0, source),
+                    _ => Err(Error::Message(format!("invalid event type: {}", type_))),
+                }
+            },
             Action::RelativeMove{code, amount100}
                 => self.move_relative(code, (amount100 as f32) / 100f32),
-            Action::KeyDown(code) => self.press_key(code, true ),
-            Action::KeyUp  (code) => self.press_key(code, false),
+            Action::AxisKeys{negative, positive, press, release} => {
+                let position = input.position.unwrap_or(input.value as f32);
+                for (key, position) in [(negative, -position), (positive, position)] {
+                    if let Some(code) = key {
+                        /* keys held by anything else are left alone */
+                        let held = self.is_pressed_by(code, source);
+                        let limit = match held {
+                            true => release,
+                            false => press,
+                        };
+                        match (held, position >= limit) {
+                            (false, true) if !self.is_pressed(code)
+                                => self.press_key_from(code, true, source)?,
+                            (true, false) => self.press_key_from(code, false, source)?,
+                            _ => {},
+                        }
+                    }
+                }
+                Ok(())
+            },
+            Action::KeyDown(code) => self.press_key_from(code, true , source),
+            Action::KeyUp  (code) => self.press_key_from(code, false, source),
+            Action::Sync => {
+                self.sync();
+                Ok(())
+            },
+            Action::Drop => Ok(()),
         }
     }
 
@@ -122,25 +353,117 @@ impl DestinationDevice {
         Ok(())
     }
 
-    pub fn press_key(&self, code: u32, press: bool) -> Result<()> {
+    /// Sets an absolute axis, writing it only if the value changes.
+    pub fn set_absolute(&self, code: u32, value: i32) -> Result<()> {
         let component_cell =
-            self.components.key.as_ref()
-                .ok_or_else(|| Error::Message("invalid component: Relative".into()))?
+            self.components.absolute.as_ref()
+                .ok_or_else(|| Error::Message("invalid component: Absolute".into()))?
             .get(code as usize)
                 .ok_or_else(|| Error::Message(format!("invalid event code: {}", code)))?
             ;
-        
+
         let mut component = component_cell.get();
+        if component.value != value {
+            self.write_event(EV_ABS, code, value)?;
+            component.value = value;
+            component_cell.set(component);
+        }
+
+        Ok(())
+    }
+
+    pub fn press_key(&self, code: u32, press: bool) -> Result<()> {
+        self.press_key_from(code, press, None)
+    }
+
+    /// Like `press_key`, but attributes the key press to `source`.
+    pub fn press_key_from(&self, code: u32, press: bool, source: Option<&DeviceId>) -> Result<()> {
+        let mut component = self.key_component(code)?.borrow_mut();
         if component.pressed != press {
             self.write_event(EV_KEY, code, if press { 1 } else { 0 })?;
 
             component.pressed = press;
-            component_cell.set(component);
+            component.owner = match press {
+                true => source.cloned(),
+                false => None,
+            };
         }
 
         Ok(())
     }
 
+    pub fn is_pressed(&self, code: u32) -> bool {
+        self.key_component(code)
+            .map(|component| component.borrow().pressed)
+            .unwrap_or(false)
+    }
+
+    /// Returns whether a key is pressed and attributed to `source`.
+    pub fn is_pressed_by(&self, code: u32, source: Option<&DeviceId>) -> bool {
+        self.key_component(code)
+            .map(|component| {
+                let component = component.borrow();
+                component.pressed && component.owner.as_ref() == source
+            })
+            .unwrap_or(false)
+    }
+
+    /// Releases every pressed key attributed to `source`, or all pressed
+    /// keys if `source` is `None`, and reports them with `SYN_REPORT`.
+    pub fn release_all(&self, source: Option<&DeviceId>) -> Result<()> {
+        let keys = self.components.key.as_ref()
+            .ok_or_else(|| Error::Message("invalid component: Key".into()))?;
+
+        let mut result = Ok(());
+        for (code, cell) in keys.iter().enumerate() {
+            let mut component = cell.borrow_mut();
+            if !component.pressed {
+                continue;
+            }
+            if source.is_some() && component.owner.as_ref() != source {
+                continue;
+            }
+
+            debug!("{}: releasing {}", self.id, EventTarget::new(EV_KEY, code as u32));
+
+            component.pressed = false;
+            component.owner = None;
+            if let Err(e) = self.write_event(EV_KEY, code as u32, 0) {
+                result = Err(e);
+            }
+        }
+
+        self.sync();
+        result
+    }
+
+    /// Returns the pressed keys, along with the source device each is
+    /// attributed to.
+    pub fn pressed_keys(&self) -> Vec<(u32, Option<DeviceId>)> {
+        let keys = match self.components.key.as_ref() {
+            Some(keys) => keys,
+            None => return Vec::new(),
+        };
+
+        keys.iter()
+            .enumerate()
+            .filter_map(|(code, cell)| {
+                let component = cell.borrow();
+                match component.pressed {
+                    true => Some((code as u32, component.owner.clone())),
+                    false => None,
+                }
+            })
+            .collect()
+    }
+
+    fn key_component(&self, code: u32) -> Result<&RefCell<KeyComponent>> {
+        self.components.key.as_ref()
+            .ok_or_else(|| Error::Message("invalid component: Key".into()))?
+        .get(code as usize)
+            .ok_or_else(|| Error::Message(format!("invalid event code: {}", code)))
+    }
+
     pub fn sync(&self) {
         if self.should_sync.get() {
             let _ = self.write_event(EV_SYN, SYN_REPORT, 0);
@@ -148,3 +471,18 @@ impl DestinationDevice {
         }
     }
 }
+
+impl Drop for DestinationDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.release_all(None) {
+            warn!("{}: can't release keys: {}", self.id, e);
+        }
+    }
+}
+
+/// Returns whether a device with the given name and identifiers looks like
+/// one of evenger's virtual devices.
+pub fn is_virtual_device(name: Option<&str>, id: InputId) -> bool {
+    id.bustype == BUS_VIRTUAL
+        && name.map(|name| name.starts_with(NAME_PREFIX)).unwrap_or(false)
+}
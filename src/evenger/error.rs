@@ -1,5 +1,9 @@
 
+use super::DeviceId;
+use super::srcdev::EventTarget;
+use nix::errno::Errno;
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum Error {
@@ -7,12 +11,61 @@ pub enum Error {
     Errno(nix::errno::Errno),
     IOError(std::io::Error),
     Message(String),
+    /// The device node of a device doesn't exist.
+    DeviceNotFound {
+        device: DeviceId,
+        path: PathBuf,
+    },
+    /// The device node of a device can't be accessed.
+    PermissionDenied {
+        device: DeviceId,
+        path: PathBuf,
+    },
+    /// A source device can't be grabbed, usually because another process
+    /// already holds it.
+    GrabFailed {
+        device: DeviceId,
+        path: PathBuf,
+        errno: Errno,
+    },
+    /// A device doesn't support an event a rule refers to.
+    CapabilityMissing {
+        device: DeviceId,
+        path: PathBuf,
+        target: EventTarget,
+    },
+    /// Any other failure of a device.
+    Device {
+        device: DeviceId,
+        path: PathBuf,
+        errno: Errno,
+    },
+    /// An invalid configuration. `line` and `col` count from 1; `col` is 0
+    /// if the error concerns the whole line.
+    Config {
+        path: Option<PathBuf>,
+        line: usize,
+        col: usize,
+        msg: String,
+    },
 }
 
 impl Error {
     pub fn msg<S: Into<String>>(msg: S) -> Self {
         Self::Message(msg.into())
     }
+
+    /// Classifies an error of accessing the node of a device.
+    pub fn device<P: AsRef<Path>>(device: &DeviceId, path: P, errno: Errno) -> Self {
+        let (device, path) = (DeviceId::clone(device), path.as_ref().to_path_buf());
+        match errno {
+            Errno::ENOENT | Errno::ENODEV | Errno::ENXIO
+                => Error::DeviceNotFound { device, path },
+            Errno::EACCES | Errno::EPERM
+                => Error::PermissionDenied { device, path },
+            errno => Error::Device { device, path, errno },
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -22,6 +75,25 @@ impl std::fmt::Display for Error {
             Error::Errno(err) => err.fmt(fmt),
             Error::IOError(err) => err.fmt(fmt),
             Error::Message(msg) => msg.fmt(fmt),
+            Error::DeviceNotFound { device, path } =>
+                write!(fmt, "device {}: {} does not exist", device, path.display()),
+            Error::PermissionDenied { device, path } =>
+                write!(fmt, "device {}: permission denied to access {}", device, path.display()),
+            Error::GrabFailed { device, path, errno } =>
+                write!(fmt, "device {}: can't grab {}: {}", device, path.display(), errno.desc()),
+            Error::CapabilityMissing { device, path, target } =>
+                write!(fmt, "device {}: {} does not support {}", device, path.display(), target),
+            Error::Device { device, path, errno } =>
+                write!(fmt, "device {}: {}: {}", device, path.display(), errno.desc()),
+            Error::Config { path, line, col, msg } => {
+                if let Some(path) = path {
+                    write!(fmt, "{}:", path.display())?;
+                }
+                match col {
+                    0 => write!(fmt, "{}: {}", line, msg),
+                    col => write!(fmt, "{}:{}: {}", line, col, msg),
+                }
+            },
         }
     }
 }
@@ -54,4 +126,4 @@ impl From<nix::Error> for Error {
             _ => Error::Message("Unknown Error".into()),
         }
     }
-}
\ No newline at end of file
+}
@@ -1,131 +1,1409 @@
 
 use crate::muxer;
-use super::{Error, Result};
-use super::destdev::{DestinationDevice};
-use super::srcdev::{SourceDeviceSet, SourceDevice, Event, Modifier};
+use super::{DeviceId, Error, Result};
+use super::axis::Axis;
+use super::stick::Stick;
+use super::config::{self, Config, ConfigWatcher, DeviceConfig, ErrorPolicy, DEFAULT_ESCAPE};
+use super::control::{self, ControlInput, ControlServer};
+use super::destdev::{Action, ActionInput, DestinationDevice, SinkKind, GAMEPAD_AXIS_MAX};
+use super::rule::{ActionRulePhase, Rule, RuleSet, TargetSet};
+use super::srcdev::{SourceDeviceSet, SourceDevice, Event, EventTarget};
+use super::vars::{self, VarValue, Variables};
+use crate::foreign::*;
 use muxer::Muxer;
-use std::{path::Path, rc::Rc};
+use nix::sys::signal::Signal;
+use serde_json::{json, Value};
+use std::{path::Path, path::PathBuf, rc::Rc};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// How often a device with `ErrorPolicy::Reopen` is tried to be reopened.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+/// How often gamepad axes on their way to where their keys push them, and
+/// the pointer pushed by sticks, are moved.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
 
 pub struct Evenger {
+    options: Options,
     muxer: Muxer,
     srcdevs: SourceDeviceSet,
     destdev: DestinationDevice,
+    /// Virtual gamepads, created from the configuration.
+    gamepads: Vec<DestinationDevice>,
+    /// Gamepad axes driven by keys.
+    axes: RefCell<Vec<Axis>>,
+    /// Analog sticks moving the pointer or scrolling.
+    sticks: RefCell<Vec<Stick>>,
+    rules: RuleSet,
+    /// Keys pressed by rule actions, along with the rules that pressed them.
+    rule_keys: RefCell<HashMap<u32, Vec<Rc<Rule>>>>,
+    /// Keys pressed by rule actions on the press of a source key, to be
+    /// released along with it whatever the rules say by then.
+    key_outputs: RefCell<HashMap<(DeviceId, u32), Vec<u32>>>,
+    /// The key pressed last on the source devices, as long as no other key
+    /// was pressed after it, for releasing it to count as a tap.
+    last_press: RefCell<Option<(DeviceId, u32)>>,
+    config: Option<ConfigSource>,
+    /// Failed devices waiting to be reopened, along with the time of the
+    /// next attempt.
+    reopening: Vec<(DeviceConfig, Instant)>,
+    /// Keys that make evenger exit when held together on the source
+    /// devices, bypassing the rules.
+    escape: Vec<u32>,
+    /// Keys that pause or resume remapping when held together.
+    pause: Vec<u32>,
+    /// Whether the pause keys were held after the last frame.
+    pause_held: Cell<bool>,
+    /// Whether remapping is paused, with the source devices ungrabbed and
+    /// their events ignored.
+    paused: Cell<bool>,
+    /// Variables set through control requests.
+    vars: Variables,
+    control: Option<ControlServer>,
+}
+
+/// The configuration file in use.
+struct ConfigSource {
+    path: PathBuf,
+    watcher: ConfigWatcher,
+    /// Devices opened at startup. These are not affected by reloading.
+    devices: Vec<DeviceConfig>,
+    /// Gamepads created at startup, likewise.
+    gamepads: Vec<DeviceId>,
+}
+
+#[derive(Clone)]
+pub struct Options {
+    /// Grab source devices exclusively.
+    pub grab: bool,
+    /// Where output events are written to.
+    pub sink: SinkKind,
+    /// Print every source event along with its translation.
+    pub monitor: bool,
+    /// Where to listen for control requests, if at all.
+    pub socket: Option<PathBuf>,
 }
 
 impl Evenger {
-    pub fn new() -> Result<Evenger> {
+    pub fn new(options: Options) -> Result<Evenger> {
         // TODO: configurable output device
 
-        let muxer = Muxer::new()
+        let mut muxer = Muxer::new()
             .map_err(|e| Error::Description("muxer".into(), Box::new(e)))?;
+        muxer.watch_signals(&[Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGUSR1])
+            .map_err(|e| Error::Description("signals".into(), Box::new(e)))?;
 
-        let destdev = DestinationDevice::new(Rc::new("output".to_string()))
-            .map_err(|e| Error::Description("destdev".into(), Box::new(e)))?;
+        let destdev = DestinationDevice::new(Rc::new("output".to_string()), options.sink)?;
+
+        let control = match &options.socket {
+            Some(path) => match ControlServer::bind(path) {
+                Ok(control) => {
+                    muxer.watch_input(control.fd())?;
+                    debug!("listening for control requests on {}", control.path().display());
+                    Some(control)
+                },
+                Err(e) => {
+                    warn!("control socket disabled: {}", e);
+                    None
+                },
+            },
+            None => None,
+        };
 
         Ok(Evenger {
+            options,
             muxer,
             srcdevs: SourceDeviceSet::new(),
             destdev,
+            gamepads: Vec::new(),
+            axes: RefCell::new(Vec::new()),
+            sticks: RefCell::new(Vec::new()),
+            rules: RuleSet::new(),
+            rule_keys: RefCell::new(HashMap::new()),
+            key_outputs: RefCell::new(HashMap::new()),
+            last_press: RefCell::new(None),
+            config: None,
+            reopening: Vec::new(),
+            escape: DEFAULT_ESCAPE.to_vec(),
+            pause: Vec::new(),
+            pause_held: Cell::new(false),
+            paused: Cell::new(false),
+            vars: Variables::new(),
+            control,
         })
     }
 
+    /// Opens the devices and installs the rules of a configuration file,
+    /// then keeps watching it for changes.
+    pub fn load_config<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let config = Config::load(path)?;
+
+        for dev in &config.devices {
+            if let Err(e) = self.open_device(dev.id.as_str(), &dev.path) {
+                self.on_device_failed(dev, e)?;
+            }
+        }
+
+        for id in &config.gamepads {
+            self.gamepads.push(DestinationDevice::gamepad(Rc::clone(id), self.options.sink)?);
+            debug!("created gamepad {}", id);
+        }
+
+        let watcher = ConfigWatcher::new(path)?;
+        self.muxer.watch_input(watcher.fd())?;
+
+        info!("loaded {} rules from {}", config.rules.len(), path.display());
+
+        self.rules = config.rules;
+        self.escape = config.escape;
+        self.pause = config.pause;
+        self.axes = RefCell::new(config.axes.into_iter().map(Axis::new).collect());
+        self.sticks = RefCell::new(config.sticks.into_iter().map(Stick::new).collect());
+        self.config = Some(ConfigSource {
+            path: path.to_path_buf(),
+            watcher,
+            devices: config.devices,
+            gamepads: config.gamepads,
+        });
+        self.check_capabilities(None);
+
+        Ok(())
+    }
+
+    /// Re-reads the configuration file and replaces the rules, keeping the
+    /// devices as they are. Keys held only by rules that no longer exist, or
+    /// changed, are released.
+    pub fn reload_config(&mut self) -> Result<()> {
+        let source = self.config.as_ref()
+            .ok_or_else(|| Error::msg("no configuration file loaded"))?;
+
+        let config = Config::load(&source.path)?;
+        if config.devices != source.devices {
+            warn!("device changes in {} take effect after restart", source.path.display());
+            config.check_devices(&source.devices)?;
+        }
+        if config.gamepads != source.gamepads {
+            warn!("gamepad changes in {} take effect after restart", source.path.display());
+            config.check_gamepads(&source.gamepads)?;
+        }
+
+        let mut vanished = Vec::new();
+        self.rule_keys.borrow_mut().retain(|code, rules| {
+            rules.retain(|rule| config.rules.contains(rule));
+            if rules.is_empty() {
+                vanished.push(*code);
+            }
+            !rules.is_empty()
+        });
+
+        self.rules = config.rules;
+        self.escape = config.escape;
+        self.pause = config.pause;
+
+        self.center_axes();
+        self.axes = RefCell::new(config.axes.into_iter().map(Axis::new).collect());
+        self.sticks = RefCell::new(config.sticks.into_iter().map(Stick::new).collect());
+
+        for code in vanished {
+            self.destdev.press_key(code, false)?;
+        }
+        self.destdev.sync();
+        self.check_capabilities(None);
+
+        info!("reloaded {} rules from {}", self.rules.len(), source.path.display());
+
+        Ok(())
+    }
+
+    /// Warns about source devices that don't support the events the rules
+    /// restricted to them refer to, since such rules never match. A rule
+    /// matching several events only needs one of them to be supported.
+    /// Likewise for the devices of sticks. Only `device` is checked, if
+    /// given.
+    fn check_capabilities(&self, device: Option<&DeviceId>) {
+        let checked = |id: &DeviceId| device.is_none() || device == Some(id);
+
+        for stick in self.sticks.borrow().iter().filter(|stick| checked(stick.device())) {
+            let target = EventTarget::new(EV_ABS, stick.code());
+            if let Some(srcdev) = self.srcdevs.get_by_id(Rc::clone(stick.device())) {
+                if !srcdev.supports(target) {
+                    warn!("{}", Error::CapabilityMissing {
+                        device: Rc::clone(stick.device()),
+                        path: srcdev.path().to_path_buf(),
+                        target,
+                    });
+                }
+            }
+        }
+
+        for rule in self.rules.iter() {
+            let targets = rule.device().map(|id| (id, rule.main().clone())).into_iter()
+                .chain(rule.condition().modifiers().into_iter()
+                    .filter_map(|modf| Some((modf.device()?, TargetSet::single(modf.target()?)))));
+
+            for (id, set) in targets.filter(|(id, _)| checked(id)) {
+                let srcdev = match self.srcdevs.get_by_id(Rc::clone(&id)) {
+                    Some(srcdev) => srcdev,
+                    None => continue,
+                };
+                if !set.targets().any(|target| srcdev.supports(target)) {
+                    warn!("{}", Error::CapabilityMissing {
+                        device: id,
+                        path: srcdev.path().to_path_buf(),
+                        target: set.targets().next().expect("target sets are not empty"),
+                    });
+                }
+            }
+        }
+    }
+
     pub fn open_device<S, P>(&mut self, id: S, devpath: P) -> Result<()> 
         where S: Into<String>,
               P: AsRef<Path>,
     {
         let id = Rc::new(id.into());
 
-        let srcdev = SourceDevice::open(Rc::clone(&id), devpath)?;
+        let grab = self.options.grab && !self.paused.get();
+        let srcdev = SourceDevice::open(Rc::clone(&id), &devpath, grab)?;
         let fd = srcdev.fd();
 
         self.muxer.watch_input(fd)?;
         self.srcdevs.push(srcdev);
 
+        debug!("opened source device {} at {}", id, devpath.as_ref().display());
+
         Ok(())
     }
 
     pub fn run(&mut self) -> Result<()> {
         loop {
-            for mux_ev in self.muxer.wait(None)? {
+            if self.srcdevs.len() == 0 && self.reopening.is_empty() {
+                info!("no source devices left");
+                break
+            }
+
+            for mux_ev in self.muxer.wait(self.timeout())? {
+                if self.muxer.signal_fd() == Some(mux_ev.fd()) {
+                    if !self.on_signal()? {
+                        self.shutdown();
+                        return Ok(());
+                    }
+                    continue;
+                }
+
+                if self.control.as_ref().map(|c| c.owns(mux_ev.fd())).unwrap_or(false) {
+                    if mux_ev.writable() {
+                        self.on_control_writable(mux_ev.fd())?;
+                    }
+                    if mux_ev.readable() || !mux_ev.writable() {
+                        self.on_control_ready(mux_ev.fd())?;
+                    }
+                    continue;
+                }
+
+                if self.config.as_ref().map(|c| c.watcher.fd()) == Some(mux_ev.fd()) {
+                    self.on_config_changed();
+                    continue;
+                }
+
                 if mux_ev.readable() {
-                    self.on_srcdev_ready(mux_ev.fd())?;
+                    match self.on_srcdev_ready(mux_ev.fd()) {
+                        Ok(true) => {},
+                        Ok(false) => {
+                            self.shutdown();
+                            return Ok(());
+                        },
+                        Err(e) => {
+                            self.on_srcdev_failed(mux_ev.fd(), e)?;
+                            continue;
+                        },
+                    }
                 }
 
                 if mux_ev.hungup() {
-                    self.srcdevs.remove_by_fd(mux_ev.fd());
+                    self.on_srcdev_hungup(mux_ev.fd())?;
                 }
             }
 
-            if self.srcdevs.len() == 0 {
-                break
+            self.reopen_devices();
+            self.update_axes();
+            self.update_sticks();
+        }
+
+        Ok(())
+    }
+
+    /// Handles pending signals. Returns `false` if evenger should exit.
+    fn on_signal(&mut self) -> Result<bool> {
+        while let Some(signal) = self.muxer.read_signal()? {
+            match signal {
+                Signal::SIGINT | Signal::SIGTERM => {
+                    info!("received {}, shutting down", signal);
+                    return Ok(false);
+                },
+                Signal::SIGHUP => {
+                    info!("received {}, reloading configuration", signal);
+                    if let Err(e) = self.reload_config() {
+                        error!("can't reload configuration: {}", e);
+                    }
+                },
+                Signal::SIGUSR1 => self.dump_state(),
+                _ => {},
             }
         }
 
+        Ok(true)
+    }
+
+    fn on_control_ready(&mut self, fd: RawFd) -> Result<()> {
+        let input = match self.control.as_mut() {
+            Some(control) => control.read(fd),
+            None => return Ok(()),
+        };
+
+        let requests = match input {
+            ControlInput::Requests(requests) => requests,
+            ControlInput::Connected(fd) => return Ok(self.muxer.watch_input(fd)?),
+            ControlInput::Nothing => return Ok(()),
+        };
+
+        for request in requests {
+            let response = match request {
+                Ok(request) => self.handle_request(&request),
+                Err(e) => control::failure(e),
+            };
+            if let Some(control) = self.control.as_mut() {
+                control.respond(fd, &response);
+            }
+        }
+
+        self.watch_control_output(fd)
+    }
+
+    fn on_control_writable(&mut self, fd: RawFd) -> Result<()> {
+        if let Some(control) = self.control.as_mut() {
+            control.flush(fd);
+        }
+        self.watch_control_output(fd)
+    }
+
+    /// Watches a control client for becoming writable while responses to it
+    /// are pending.
+    fn watch_control_output(&self, fd: RawFd) -> Result<()> {
+        match self.control.as_ref().and_then(|control| control.has_output(fd)) {
+            Some(pending) => Ok(self.muxer.watch_output(fd, pending)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Carries out a control request and returns the response.
+    fn handle_request(&mut self, request: &Value) -> Value {
+        let command = match request.get("command").and_then(Value::as_str) {
+            Some(command) => command,
+            None => return control::failure("expected a `command`"),
+        };
+        debug!("control request: {}", request);
+
+        match command {
+            "status" => control::success(self.status()),
+            "pause" | "resume" => match self.set_paused(command == "pause") {
+                Ok(()) => control::success(json!({ "paused": self.paused.get() })),
+                Err(e) => control::failure(e),
+            },
+            "set" => match self.set_variable(request) {
+                Ok(()) => control::success(json!({})),
+                Err(e) => control::failure(e),
+            },
+            "layer" => match self.set_layer(request) {
+                Ok(()) => control::success(json!({ "layer": self.vars.get(vars::LAYER).map(VarValue::to_string) })),
+                Err(e) => control::failure(e),
+            },
+            "inject" => match self.inject(request) {
+                Ok(count) => control::success(json!({ "actions": count })),
+                Err(e) => control::failure(e),
+            },
+            "reload" => match self.reload_config() {
+                Ok(()) => control::success(json!({ "rules": self.rules.len() })),
+                Err(e) => control::failure(e),
+            },
+            _ => control::failure(format!("unknown command: {}", command)),
+        }
+    }
+
+    /// Sets or unsets the variable `name` of a `set` request to `value`,
+    /// which can be a string, a boolean or an integer. A missing or null
+    /// `value` unsets the variable.
+    fn set_variable(&mut self, request: &Value) -> Result<()> {
+        let name = request.get("name").and_then(Value::as_str)
+            .ok_or_else(|| Error::msg("expected a `name`"))?;
+        if !vars::is_valid_name(name) {
+            return Err(Error::Message(format!("invalid variable name: {}", name)));
+        }
+
+        let value = match request.get("value") {
+            None | Some(Value::Null) => None,
+            Some(Value::Bool(value)) => Some(VarValue::Bool(*value)),
+            Some(Value::String(value)) => Some(VarValue::Str(value.clone())),
+            Some(value) => Some(VarValue::Int(value.as_i64()
+                .ok_or_else(|| Error::msg("`value` must be a string, boolean or integer"))?)),
+        };
+
+        match &value {
+            Some(value) => debug!("${} = {}", name, value),
+            None => debug!("${} unset", name),
+        }
+        self.vars.set(name, value);
         Ok(())
     }
 
-    fn on_srcdev_ready(&self, fd: RawFd) -> Result<()> {
+    /// Switches to the layer `name` of a `layer` request, or back to no layer
+    /// if the name is missing or null. The layer is the variable `$layer`,
+    /// so rules of a layer are the ones with `if $layer=<name>`.
+    fn set_layer(&mut self, request: &Value) -> Result<()> {
+        let name = match request.get("name") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(name)) => Some(VarValue::Str(name.clone())),
+            Some(_) => return Err(Error::msg("`name` must be a string")),
+        };
+
+        match &name {
+            Some(name) => info!("switched to layer {}", name),
+            None => info!("left layer {}", self.vars.get(vars::LAYER)
+                .map_or_else(String::new, VarValue::to_string)),
+        }
+        self.vars.set(vars::LAYER, name);
+        Ok(())
+    }
+
+    /// Performs the actions of an `inject` request on an output device. The
+    /// actions are given in the syntax of the configuration, either in a
+    /// string or in an array of strings. `value` actions transform `value`,
+    /// which defaults to 1.
+    ///
+    /// Requests are handled between source frames, so injected events are
+    /// written as frames of their own.
+    fn inject(&self, request: &Value) -> Result<usize> {
+        if self.paused.get() {
+            return Err(Error::msg("remapping is paused"));
+        }
+
+        let device = match request.get("device") {
+            None => None,
+            Some(Value::String(device)) => Some(device.as_str()),
+            Some(_) => return Err(Error::msg("`device` must be a string")),
+        };
+        let destdev = self.destination(device)?;
+
+        let actions = match request.get("actions") {
+            Some(Value::String(text)) => config::parse_actions(text)?,
+            Some(Value::Array(items)) => {
+                let mut actions = Vec::new();
+                for item in items {
+                    let text = item.as_str()
+                        .ok_or_else(|| Error::msg("`actions` must hold strings"))?;
+                    actions.extend(config::parse_actions(text)?);
+                }
+                actions
+            },
+            _ => return Err(Error::msg("expected `actions`")),
+        };
+
+        let value = match request.get("value") {
+            None => 1,
+            Some(value) => value.as_i64()
+                .map(|value| value as i32)
+                .ok_or_else(|| Error::msg("`value` must be an integer"))?,
+        };
+
+        debug!("{}: injecting {} actions", destdev.id(), actions.len());
+        for action in &actions {
+            destdev.perform_action(action, ActionInput { value, position: None }, None)?;
+        }
+        destdev.sync();
+
+        Ok(actions.len())
+    }
+
+    /// Looks up an output device by id, or returns the default one.
+    fn destination(&self, id: Option<&str>) -> Result<&DestinationDevice> {
+        match id {
+            None => Ok(&self.destdev),
+            Some(id) if id == self.destdev.id().as_str() => Ok(&self.destdev),
+            Some(id) => self.gamepads.iter()
+                .find(|gamepad| gamepad.id().as_str() == id)
+                .ok_or_else(|| Error::Message(format!("unknown output device: {}", id))),
+        }
+    }
+
+    /// Describes the source devices, rules and held keys.
+    fn status(&self) -> Value {
+        let devices: Vec<Value> = self.srcdevs.iter()
+            .map(|srcdev| json!({
+                "id": srcdev.id().as_str(),
+                "path": srcdev.path().to_string_lossy(),
+                "pressed": key_list(srcdev.pressed_keys()),
+            }))
+            .collect();
+        let reopening: Vec<Value> = self.reopening.iter()
+            .map(|(dev, _)| json!({
+                "id": dev.id.as_str(),
+                "path": dev.path.to_string_lossy(),
+            }))
+            .collect();
+        let output: Vec<Value> = self.destdev.pressed_keys().into_iter()
+            .map(|(code, owner)| json!({
+                "key": EventTarget::new(EV_KEY, code).to_string(),
+                "owner": owner.as_ref().map(|id| id.as_str()),
+            }))
+            .collect();
+        let axes: Vec<Value> = self.axes.borrow().iter()
+            .map(|axis| json!({
+                "output": axis.output().as_str(),
+                "axis": EventTarget::new(EV_ABS, axis.code()).to_string(),
+                "position": axis.position(),
+            }))
+            .collect();
+
+        json!({
+            "devices": devices,
+            "reopening": reopening,
+            "rules": self.rules.len(),
+            "paused": self.paused.get(),
+            "variables": self.vars.iter()
+                .map(|(name, value)| (name.clone(), match value {
+                    VarValue::Bool(value) => json!(value),
+                    VarValue::Int(value) => json!(value),
+                    VarValue::Str(value) => json!(value),
+                }))
+                .collect::<serde_json::Map<_, _>>(),
+            "config": self.config.as_ref().map(|c| c.path.to_string_lossy()),
+            "output": output,
+            "axes": axes,
+        })
+    }
+
+    /// Releases the keys held on the output and closes the source devices,
+    /// which ungrabs them. The output device goes away when evenger is
+    /// dropped.
+    fn shutdown(&mut self) {
+        if let Err(e) = self.destdev.release_all(None) {
+            error!("can't release keys: {}", e);
+        }
+        self.rule_keys.borrow_mut().clear();
+        self.key_outputs.borrow_mut().clear();
+        self.center_axes();
+        self.srcdevs.clear();
+        self.reopening.clear();
+    }
+
+    /// Logs the source devices, rules and held keys.
+    fn dump_state(&self) {
+        info!("state: {} source devices, {} rules{}", self.srcdevs.len(), self.rules.len(),
+            if self.paused.get() { ", paused" } else { "" });
+        for srcdev in self.srcdevs.iter() {
+            info!("  source {} at {}: holding [{}]", srcdev.id(), srcdev.path().display(),
+                key_names(srcdev.pressed_keys()));
+        }
+        for (dev, _) in &self.reopening {
+            info!("  source {} at {}: waiting to be reopened", dev.id, dev.path.display());
+        }
+
+        for (code, owner) in self.destdev.pressed_keys() {
+            let owner = match owner {
+                Some(id) => id.to_string(),
+                None => "-".to_string(),
+            };
+            info!("  output {}: {} held by {}", self.destdev.id(),
+                EventTarget::new(EV_KEY, code), owner);
+        }
+        for (code, rules) in self.rule_keys.borrow().iter() {
+            for rule in rules {
+                info!("  {} pressed by rule {}", EventTarget::new(EV_KEY, *code), rule);
+            }
+        }
+        for axis in self.axes.borrow().iter() {
+            info!("  gamepad {}: {} at {:.2}", axis.output(),
+                EventTarget::new(EV_ABS, axis.code()), axis.position());
+        }
+    }
+
+    fn on_config_changed(&mut self) {
+        let changed = match &self.config {
+            Some(source) => source.watcher.changed(),
+            None => return,
+        };
+
+        let result = match changed {
+            Ok(true) => self.reload_config(),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("can't reload configuration: {}", e);
+        }
+    }
+
+    fn on_srcdev_hungup(&mut self, fd: RawFd) -> Result<()> {
+        let error = match self.srcdevs.get_by_fd(fd) {
+            Some(srcdev) => Error::device(&srcdev.id(), srcdev.path(), nix::errno::Errno::ENODEV),
+            None => return Ok(()),
+        };
+
+        self.on_srcdev_failed(fd, error)
+    }
+
+    /// Closes a source device that failed, then deals with it according to
+    /// its error policy.
+    fn on_srcdev_failed(&mut self, fd: RawFd, error: Error) -> Result<()> {
+        let srcdev = match self.srcdevs.get_by_fd(fd) {
+            Some(srcdev) => srcdev,
+            None => {
+                error!("{}", error);
+                return Ok(());
+            },
+        };
+        let id = srcdev.id();
+
+        if let Err(e) = self.destdev.release_all(Some(&id)) {
+            error!("can't release keys held by {}: {}", id, e);
+        }
+        self.key_outputs.borrow_mut().retain(|(device, _), _| *device != id);
+        self.srcdevs.remove_by_fd(fd);
+
+        /* gamepad axes let go of the keys the device held */
+        let now = Instant::now();
+        for axis in self.axes.borrow_mut().iter_mut() {
+            axis.release_keys(now, |device, code| match device {
+                Some(device) => *device != id,
+                None => self.srcdevs.keys_held(&[code]),
+            });
+        }
+        self.update_axes();
+
+        let dev = self.config.as_ref()
+            .and_then(|source| source.devices.iter().find(|dev| dev.id == id))
+            .cloned()
+            .unwrap_or_else(|| DeviceConfig {
+                id,
+                path: srcdev.path().to_path_buf(),
+                on_error: ErrorPolicy::default(),
+            });
+
+        self.on_device_failed(&dev, error)
+    }
+
+    /// Applies the error policy of a device that couldn't be opened or
+    /// failed later on. Only `ErrorPolicy::Exit` passes the error on.
+    fn on_device_failed(&mut self, dev: &DeviceConfig, error: Error) -> Result<()> {
+        match dev.on_error {
+            ErrorPolicy::Exit => Err(error),
+            ErrorPolicy::Drop => {
+                error!("{}; carrying on without device {}", error, dev.id);
+                Ok(())
+            },
+            ErrorPolicy::Reopen => {
+                error!("{}; trying to reopen device {}", error, dev.id);
+                self.reopening.push((dev.clone(), Instant::now() + REOPEN_INTERVAL));
+                Ok(())
+            },
+        }
+    }
+
+    /// Returns how long to wait for events before devices are due to be
+    /// reopened or axes or the pointer to be moved.
+    fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let moving = self.axes.borrow().iter().any(Axis::is_ramping)
+            || self.sticks.borrow().iter().any(Stick::is_moving);
+
+        self.reopening.iter()
+            .map(|(_, at)| at.saturating_duration_since(now))
+            .chain(if moving { Some(TICK_INTERVAL) } else { None })
+            .min()
+    }
+
+    fn reopen_devices(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.reopening).into_iter()
+            .partition::<Vec<_>, _>(|(_, at)| *at <= now);
+        self.reopening = waiting;
+
+        for (dev, _) in due {
+            match self.open_device(dev.id.as_str(), &dev.path) {
+                Ok(()) => {
+                    info!("reopened source device {}", dev.id);
+                    self.check_capabilities(Some(&dev.id));
+                },
+                Err(e) => {
+                    debug!("can't reopen source device {}: {}", dev.id, e);
+                    self.reopening.push((dev, now + REOPEN_INTERVAL));
+                },
+            }
+        }
+    }
+
+    /// Reads and translates the pending frames of a source device. Returns
+    /// `false` if the escape chord was pressed and evenger should exit.
+    fn on_srcdev_ready(&self, fd: RawFd) -> Result<bool> {
         let srcdev = self.srcdevs.get_by_fd(fd)
             .ok_or_else(|| Error::msg("invalid fd"))?;
 
-        loop {
-            match srcdev.read_event()? {
-                Some(event) => {
-                    self.translate_event(&event)?;
+        while let Some(mut frame) = srcdev.read_frame()? {
+            for event in &frame.events {
+                trace!("{}: {} {}", event.srcdev_id(), event.target(), event.value());
+            }
+
+            if self.srcdevs.keys_held(&self.escape) {
+                warn!("escape chord [{}] pressed, exiting", key_names(self.escape.iter().cloned()));
+                return Ok(false);
+            }
+
+            let pause_held = !self.pause.is_empty() && self.srcdevs.keys_held(&self.pause);
+            if pause_held && !self.pause_held.get() {
+                /* failures are logged, and affect other devices too */
+                let _ = self.set_paused(!self.paused.get());
+            }
+            self.pause_held.set(pause_held);
+
+            if self.paused.get() {
+                continue;
+            }
+
+            self.mark_taps(&mut frame.events);
+            frame.events.retain(|event| {
+                let takers = self.take_event(event);
+                if self.options.monitor && !takers.is_empty() {
+                    println!("{}: {} {}\n    taken by: {}",
+                        event.srcdev_id(), event.target(), event.value(), takers.join(", "));
+                }
+                takers.is_empty()
+            });
+            self.update_axes();
+            self.update_sticks();
+
+            if self.options.monitor {
+                self.monitor_frame(&frame.events);
+            } else {
+                self.translate_frame(&frame.events);
+            }
+
+            if frame.resync {
+                self.reconcile(&srcdev.id(), |code| srcdev.is_pressed(code));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Marks the releases of keys pressed with no other key pressed in
+    /// between as taps.
+    fn mark_taps(&self, events: &mut [Event]) {
+        let mut last_press = self.last_press.borrow_mut();
+        for event in events {
+            let target = event.target();
+            if target.type_() != EV_KEY {
+                continue;
+            }
+
+            let key = (event.srcdev_id(), target.code());
+            match event.value() {
+                1 => *last_press = Some(key),
+                0 if last_press.as_ref() == Some(&key) => {
+                    event.set_tap(true);
+                    *last_press = None;
                 },
-                None => break,
+                _ => {},
             }
         }
+    }
 
-        Ok(())
+    /// Passes `event` to the axes driven by its key, and returns those
+    /// axes along with the sticks it comes from. An event taken by any of
+    /// them is not translated otherwise.
+    fn take_event(&self, event: &Event) -> Vec<String> {
+        let now = Instant::now();
+        let mut takers = Vec::new();
+        for axis in self.axes.borrow_mut().iter_mut() {
+            if axis.handle(event, now) {
+                takers.push(format!("axis {}", axis));
+            }
+        }
+        for stick in self.sticks.borrow().iter().filter(|stick| stick.handles(event)) {
+            takers.push(format!("stick {}", stick));
+        }
+        takers
     }
 
-    fn translate_event(&self, event: &Event) -> Result<()> {
-        use crate::foreign::*;
+    /// Moves the axes and writes their positions to the gamepads. Failures
+    /// are logged.
+    fn update_axes(&self) {
+        let now = Instant::now();
+        for axis in self.axes.borrow_mut().iter_mut() {
+            let value = (axis.update(now) * GAMEPAD_AXIS_MAX as f32).round() as i32;
+            let gamepad = match self.gamepads.iter().find(|gamepad| gamepad.id() == *axis.output()) {
+                Some(gamepad) => gamepad,
+                None => continue,
+            };
+            if let Err(e) = gamepad.set_absolute(axis.code(), value) {
+                error!("{}: can't move {}: {}", gamepad.id(), EventTarget::new(EV_ABS, axis.code()), e);
+            }
+        }
 
-        let target = event.target();
+        for gamepad in &self.gamepads {
+            gamepad.sync();
+        }
+    }
+
+    /// Moves the pointer or scrolls by the positions of the sticks, for the
+    /// time since the last update. Nothing moves while remapping is paused.
+    /// Failures are logged.
+    fn update_sticks(&self) {
+        let now = Instant::now();
+        for stick in self.sticks.borrow_mut().iter_mut() {
+            let position = match self.srcdevs.get_by_id(Rc::clone(stick.device())) {
+                Some(srcdev) if !self.paused.get() => srcdev.get_event_state(EV_ABS, stick.code())
+                    .and_then(|value| srcdev.abs_position(stick.code(), value)),
+                _ => None,
+            };
+            let position = match position {
+                Some(position) => position,
+                None => {
+                    stick.stop();
+                    continue;
+                },
+            };
 
-        match (target.type_(), target.code()) {
-            (EV_REL, REL_Y) => {
-                let mouse_dev = self.srcdevs.get_by_id(Rc::new("mouse".to_string()))
-                    .ok_or_else(|| Error::msg("can't get device 'mouse'"))?;
+            let distance = stick.update(position, now);
+            if let Err(e) = self.destdev.move_relative(stick.output(), distance) {
+                error!("{}: can't move {}: {}", self.destdev.id(),
+                    EventTarget::new(EV_REL, stick.output()), e);
+            }
+        }
+
+        self.destdev.sync();
+    }
 
-                if Some(true) == mouse_dev.match_modifier(Modifier::Key(BTN_TASK, true)) {
-                    /* mapping REL to REL */
-                    self.destdev.move_relative(REL_WHEEL,
-                        event.value() as f32 / -16.0f32)?;
-                    return Ok(());
+    /// Lets go of the keys driving the axes, and centers them.
+    fn center_axes(&self) {
+        for axis in self.axes.borrow_mut().iter_mut() {
+            axis.reset();
+        }
+        self.update_axes();
+    }
+
+    /// Pauses or resumes remapping. Keys held on the output are released
+    /// either way. While paused, the source devices are ungrabbed, so that
+    /// their events reach other programs unchanged, and evenger ignores them.
+    ///
+    /// Failures are logged, and the last one is returned.
+    pub fn set_paused(&self, paused: bool) -> Result<()> {
+        if self.paused.get() == paused {
+            return Ok(());
+        }
+
+        let mut result = self.destdev.release_all(None);
+        if let Err(e) = &result {
+            error!("can't release keys: {}", e);
+        }
+        self.rule_keys.borrow_mut().clear();
+        self.key_outputs.borrow_mut().clear();
+        self.center_axes();
+        self.paused.set(paused);
+
+        if self.options.grab {
+            for srcdev in self.srcdevs.iter() {
+                if let Err(e) = srcdev.grab(!paused) {
+                    error!("{}", e);
+                    result = Err(e);
                 }
-            },
-            (EV_KEY, KEY_CAPSLOCK) => {
-                let keyboard_dev = self.srcdevs.get_by_id(Rc::new("keyboard".to_string()))
-                    .ok_or_else(|| Error::msg("can't get device 'keyboard'"))?;
+            }
+        }
 
-                if Some(true) == keyboard_dev.match_modifier(Modifier::Led(LED_CAPSL, true)) {
-                    if event.value() == /* down */1  {
-                        /* ignore */
-                        return Ok(());
-                    }
+        info!("remapping {}", if paused { "paused" } else { "resumed" });
+        result
+    }
+
+    /// Brings the output in line with a source device after it has been
+    /// resynchronized. The resynchronized frame has already been translated,
+    /// so this only catches what the rules missed: keys the device passed
+    /// through but no longer holds, keys pressed by rules on keys the device
+    /// no longer holds, and keys driving gamepad axes. Keys pressed by rules
+    /// on other events, like `keys` on an axis, follow those events.
+    ///
+    /// `is_pressed` tells whether the device `id` holds a key. Failures are
+    /// logged.
+    fn reconcile<F: Fn(u32) -> bool>(&self, id: &DeviceId, is_pressed: F) {
+        for (code, owner) in self.destdev.pressed_keys() {
+            if owner.as_ref() != Some(id) {
+                continue;
+            }
+
+            let stale = match self.rule_keys.borrow().get(&code) {
+                Some(rules) => rules.iter().all(|rule| rule.main().type_() == EV_KEY
+                    && !rule.main().targets().any(|target| is_pressed(target.code()))),
+                None => !is_pressed(code),
+            };
+            if !stale {
+                continue;
+            }
+
+            debug!("{}: {} no longer held after resync", id, EventTarget::new(EV_KEY, code));
+            self.rule_keys.borrow_mut().remove(&code);
+            if let Err(e) = self.destdev.press_key(code, false) {
+                error!("{}: can't release {}: {}", self.destdev.id(), EventTarget::new(EV_KEY, code), e);
+            }
+        }
+        self.destdev.sync();
+
+        let now = Instant::now();
+        for axis in self.axes.borrow_mut().iter_mut() {
+            axis.release_keys(now, |device, code| match device {
+                Some(device) if device != id => true,
+                Some(_) => is_pressed(code),
+                None => self.srcdevs.keys_held(&[code]),
+            });
+        }
+        self.update_axes();
+    }
+
+    fn monitor_frame(&self, frame: &[Event]) {
+        /* axes and sticks may have taken every event */
+        if frame.is_empty() {
+            return;
+        }
+
+        self.destdev.start_capture();
+        let matched = self.translate_frame(frame);
+        let output = self.destdev.take_capture();
+
+        for (event, matched) in frame.iter().zip(matched) {
+            let matched = match matched.is_empty() {
+                true => "-".to_string(),
+                false => matched.iter()
+                    .map(|rule| rule.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            };
+
+            println!("{}: {} {}\n    rules: {}",
+                event.srcdev_id(), event.target(), event.value(), matched);
+        }
+
+        let output = match output.is_empty() {
+            true => "-".to_string(),
+            false => output.iter()
+                .map(|(target, value)| format!("{} {}", target, value))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!("    output: {}", output);
+    }
+
+    /// Translates a frame of source events into output events, returning
+    /// the rules that matched each event.
+    ///
+    /// Pre-actions, the translated events and post-actions are written as a
+    /// single output frame, except that taps and chords end a frame between
+    /// pressing and releasing their keys.
+    fn translate_frame(&self, frame: &[Event]) -> Vec<Vec<Rc<Rule>>> {
+        let matched: Vec<Vec<Rc<Rule>>> = frame.iter()
+            .map(|event| self.rules.match_rules(event, &self.srcdevs, &self.vars))
+            .collect();
+
+        for (event, rules) in frame.iter().zip(&matched) {
+            self.perform_actions(rules, ActionRulePhase::PreAction, event);
+        }
+
+        for (event, rules) in frame.iter().zip(&matched) {
+            let replaced = rules.iter()
+                .any(|rule| rule.actions(ActionRulePhase::PeriAction, event).next().is_some());
+            if replaced {
+                self.perform_actions(rules, ActionRulePhase::PeriAction, event);
+                continue;
+            }
+
+            let target = event.target();
+            if let Err(e) = self.destdev.forward_event(
+                &event.srcdev_id(), target.type_(), target.code(), event.value())
+            {
+                error!("passthru failure (type={} code={} value={}): {}",
+                    target.type_(), target.code(), event.value(), e);
+            }
+        }
+
+        for (event, rules) in frame.iter().zip(&matched) {
+            self.perform_actions(rules, ActionRulePhase::PostAction, event);
+        }
+        for event in frame {
+            self.release_outputs(event);
+        }
+        self.destdev.sync();
+
+        matched
+    }
+
+    /// Performs the actions of `phase` that `event` triggers. Failures only
+    /// concern the output, so they are logged rather than blamed on the
+    /// source device.
+    fn perform_actions(&self, rules: &[Rc<Rule>], phase: ActionRulePhase, event: &Event) {
+        for rule in rules {
+            for action in rule.actions(phase, event) {
+                let input = ActionInput {
+                    value: event.value(),
+                    position: event.position(),
+                };
+                if let Err(e) = self.destdev.perform_action(&action, input, Some(&event.srcdev_id())) {
+                    error!("{}: can't perform an action of rule {}: {}", self.destdev.id(), rule, e);
+                    continue;
                 }
-            },
-            (EV_KEY, KEY_LEFTSHIFT) => {
-                let keyboard_dev = self.srcdevs.get_by_id(Rc::new("keyboard".to_string()))
-                    .ok_or_else(|| Error::msg("can't get device 'keyboard'"))?;
 
-                if Some(true) == keyboard_dev.match_modifier(Modifier::Led(LED_CAPSL, true)) {
-                    self.destdev.press_key(KEY_CAPSLOCK, true)?;
-                    self.destdev.press_key(KEY_CAPSLOCK, false)?;
+                match action {
+                    Action::KeyDown(code) => self.hold_rule_key(code, rule, event),
+                    Action::KeyUp(code) => {
+                        self.rule_keys.borrow_mut().remove(&code);
+                    },
+                    Action::Value{type_: EV_KEY, code, ..} => match self.destdev.is_pressed(code) {
+                        true => self.hold_rule_key(code, rule, event),
+                        false => {
+                            self.rule_keys.borrow_mut().remove(&code);
+                        },
+                    },
+                    Action::AxisKeys{negative, positive, ..} => {
+                        for code in negative.into_iter().chain(positive) {
+                            match self.destdev.is_pressed_by(code, Some(&event.srcdev_id())) {
+                                true => self.hold_rule_key(code, rule, event),
+                                false => self.drop_rule_key(code, rule),
+                            }
+                        }
+                    },
+                    _ => {},
                 }
-            },
-            _ => {},
+            }
+        }
+    }
+
+    /// Records that `rule` holds `code` pressed, along with the other rules
+    /// that pressed it, and the source key whose press `event` pressed it.
+    fn hold_rule_key(&self, code: u32, rule: &Rc<Rule>, event: &Event) {
+        let mut rule_keys = self.rule_keys.borrow_mut();
+        let rules = rule_keys.entry(code).or_default();
+        if !rules.iter().any(|other| Rc::ptr_eq(other, rule)) {
+            rules.push(Rc::clone(rule));
+        }
+
+        let target = event.target();
+        if target.type_() == EV_KEY && event.value() == 1 {
+            let mut key_outputs = self.key_outputs.borrow_mut();
+            let codes = key_outputs.entry((event.srcdev_id(), target.code())).or_default();
+            if !codes.contains(&code) {
+                codes.push(code);
+            }
+        }
+    }
+
+    /// Releases the keys that rules pressed on the press of the source key
+    /// `event` releases, unless they have been released since or another
+    /// source key held down pressed them too. Failures are logged.
+    fn release_outputs(&self, event: &Event) {
+        let target = event.target();
+        if target.type_() != EV_KEY || event.value() != 0 {
+            return;
+        }
+
+        let codes = match self.key_outputs.borrow_mut().remove(&(event.srcdev_id(), target.code())) {
+            Some(codes) => codes,
+            None => return,
         };
+        for code in codes {
+            let shared = self.key_outputs.borrow().values().any(|codes| codes.contains(&code));
+            if shared || self.rule_keys.borrow_mut().remove(&code).is_none() {
+                continue;
+            }
+            if let Err(e) = self.destdev.press_key_from(code, false, Some(&event.srcdev_id())) {
+                error!("{}: can't release {}: {}", self.destdev.id(), EventTarget::new(EV_KEY, code), e);
+            }
+        }
+    }
 
-        if let Err(e) = self.destdev.write_event(target.type_(), target.code(), event.value()) {
-            eprintln!("passthru failure (type={} code={} value={}): {}",
-                target.type_(), target.code(), event.value(), e);
+    /// Records that `rule` no longer holds `code`, which other rules may
+    /// still hold.
+    fn drop_rule_key(&self, code: u32, rule: &Rc<Rule>) {
+        let mut rule_keys = self.rule_keys.borrow_mut();
+        if let Some(rules) = rule_keys.get_mut(&code) {
+            rules.retain(|other| !Rc::ptr_eq(other, rule));
+            if rules.is_empty() {
+                rule_keys.remove(&code);
+            }
         }
+    }
+}
 
-        Ok(())
+fn key_list<I: IntoIterator<Item = u32>>(codes: I) -> Vec<String> {
+    codes.into_iter()
+        .map(|code| EventTarget::new(EV_KEY, code).to_string())
+        .collect()
+}
+
+fn key_names<I: IntoIterator<Item = u32>>(codes: I) -> String {
+    key_list(codes).join(", ")
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            grab: true,
+            sink: SinkKind::UInput,
+            monitor: false,
+            socket: Some(PathBuf::from(control::DEFAULT_SOCKET)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evdev::InputEvent;
+
+    /// Starts evenger without devices, on the rules of `config`, written to a
+    /// file named after `name`.
+    fn evenger(name: &str, config: &str) -> (Evenger, PathBuf) {
+        let path = std::env::temp_dir()
+            .join(format!("evenger-test-{}-{}.conf", std::process::id(), name));
+        std::fs::write(&path, config).unwrap();
+
+        let mut evenger = Evenger::new(Options {
+            grab: false,
+            sink: SinkKind::Discard,
+            monitor: false,
+            socket: None,
+        }).unwrap();
+        evenger.load_config(&path).unwrap();
+        (evenger, path)
+    }
+
+    fn event(device: &str, type_: u32, code: u32, value: i32) -> Event {
+        Event::new(Rc::new(device.to_string()), InputEvent::new(type_, code, value))
+    }
+
+    fn key(code: u32, value: i32) -> Event {
+        event("kbd", EV_KEY, code, value)
+    }
+
+    fn sync() -> (EventTarget, i32) {
+        (EventTarget::new(EV_SYN, SYN_REPORT), 0)
+    }
+
+    fn out(code: u32, value: i32) -> (EventTarget, i32) {
+        (EventTarget::new(EV_KEY, code), value)
+    }
+
+    /// Translates a source frame and returns what was written to the output.
+    fn translate(evenger: &Evenger, mut frame: Vec<Event>) -> Vec<(EventTarget, i32)> {
+        evenger.destdev.start_capture();
+        evenger.mark_taps(&mut frame);
+        evenger.translate_frame(&frame);
+        evenger.destdev.take_capture()
+    }
+
+    #[test]
+    fn frames_end_once() {
+        let (evenger, path) = evenger("frames", "rule KEY_A => press KEY_B\n");
+        assert_eq!(translate(&evenger, vec![key(KEY_A, 1), key(KEY_C, 1)]),
+            vec![out(KEY_B, 1), out(KEY_C, 1), sync()]);
+        assert_eq!(translate(&evenger, vec![]), vec![]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn taps_and_chords_end_a_frame_in_between() {
+        let (evenger, path) = evenger("taps",
+            "rule KEY_A=press => pre tap KEY_B\nrule KEY_C=press => chord KEY_LEFTCTRL+KEY_V\n");
+        assert_eq!(translate(&evenger, vec![key(KEY_A, 1)]),
+            vec![out(KEY_B, 1), sync(), out(KEY_B, 0), out(KEY_A, 1), sync()]);
+        assert_eq!(translate(&evenger, vec![key(KEY_C, 1)]),
+            vec![out(KEY_LEFTCTRL, 1), out(KEY_V, 1), sync(), out(KEY_V, 0), out(KEY_LEFTCTRL, 0), sync()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resync_releases_keys_no_longer_held() {
+        let (evenger, path) = evenger("resync", "rule KEY_A => value KEY_B\n");
+        translate(&evenger, vec![key(KEY_A, 1), key(KEY_C, 1), key(KEY_D, 1)]);
+
+        /* only the keys of the resynchronized device count */
+        evenger.destdev.start_capture();
+        evenger.reconcile(&Rc::new("mouse".to_string()), |_| false);
+        assert_eq!(evenger.destdev.take_capture(), vec![]);
+
+        /* KEY_B is held by a rule on KEY_A, the others were passed through */
+        evenger.destdev.start_capture();
+        evenger.reconcile(&Rc::new("kbd".to_string()), |code| code == KEY_C);
+        assert_eq!(evenger.destdev.take_capture(),
+            vec![out(KEY_D, 0), out(KEY_B, 0), sync()]);
+        assert!(evenger.destdev.is_pressed(KEY_C));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reload_releases_keys_of_changed_rules() {
+        let (mut evenger, path) = evenger("reload", "\
+            rule [left] KEY_H => value KEY_LEFT\n\
+            rule KEY_J => value KEY_DOWN\n\
+            rule KEY_K => value KEY_UP\n\
+            rule KEY_L => value KEY_RIGHT\n");
+        translate(&evenger, vec![key(KEY_H, 1), key(KEY_J, 1), key(KEY_K, 1), key(KEY_L, 1)]);
+
+        /* unnamed rules are still the same when others move them around */
+        std::fs::write(&path, "\
+            rule [left] KEY_H => value KEY_HOME\n\
+            rule KEY_A => drop\n\
+            rule KEY_J => value KEY_DOWN\n\
+            rule KEY_L => value KEY_END\n").unwrap();
+        evenger.destdev.start_capture();
+        evenger.reload_config().unwrap();
+        let mut output = evenger.destdev.take_capture();
+
+        assert_eq!(output.pop(), Some(sync()));
+        output.sort_by_key(|(target, _)| target.code());
+        assert_eq!(output, vec![out(KEY_UP, 0), out(KEY_LEFT, 0), out(KEY_RIGHT, 0)]);
+        assert!(evenger.destdev.is_pressed(KEY_DOWN));
+
+        /* the rule kept its key, to release it as usual */
+        assert_eq!(translate(&evenger, vec![key(KEY_J, 0)]), vec![out(KEY_DOWN, 0), sync()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn releasing_a_key_releases_what_its_press_pressed() {
+        let (mut evenger, path) = evenger("routing", "\
+            rule KEY_H if $layer=nav => value KEY_LEFT\n\
+            rule KEY_1=press if $fn => press KEY_F1\n\
+            rule KEY_1=release if $fn => release KEY_F1\n");
+
+        /* the layer is left before the key is released */
+        evenger.set_layer(&json!({ "name": "nav" })).unwrap();
+        assert_eq!(translate(&evenger, vec![key(KEY_H, 1)]), vec![out(KEY_LEFT, 1), sync()]);
+        evenger.set_layer(&json!({})).unwrap();
+        assert_eq!(translate(&evenger, vec![key(KEY_H, 0)]),
+            vec![out(KEY_H, 0), out(KEY_LEFT, 0), sync()]);
+        assert!(!evenger.destdev.is_pressed(KEY_LEFT));
+
+        /* keys released by the rules aren't released again */
+        evenger.set_layer(&json!({ "name": "nav" })).unwrap();
+        translate(&evenger, vec![key(KEY_H, 1)]);
+        assert_eq!(translate(&evenger, vec![key(KEY_H, 0)]), vec![out(KEY_LEFT, 0), sync()]);
+
+        /* nor are keys another source key still holds */
+        evenger.set_variable(&json!({ "name": "fn", "value": true })).unwrap();
+        assert_eq!(translate(&evenger, vec![key(KEY_1, 1)]), vec![out(KEY_F1, 1), sync()]);
+        translate(&evenger, vec![event("other", EV_KEY, KEY_1, 1)]);
+        evenger.set_variable(&json!({ "name": "fn" })).unwrap();
+        translate(&evenger, vec![key(KEY_1, 0)]);
+        assert!(evenger.destdev.is_pressed(KEY_F1));
+        assert_eq!(translate(&evenger, vec![event("other", EV_KEY, KEY_1, 0)]),
+            vec![out(KEY_1, 0), out(KEY_F1, 0), sync()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn triggers_pick_values() {
+        let (evenger, path) = evenger("triggers", "\
+            rule KEY_A=repeat => drop\n\
+            rule KEY_B=release => press KEY_C\n\
+            rule KEY_D=2 => press KEY_E\n\
+            rule KEY_F => on press press KEY_G, on release release KEY_G\n");
+
+        assert_eq!(translate(&evenger, vec![key(KEY_A, 1), key(KEY_A, 2), key(KEY_A, 0)]),
+            vec![out(KEY_A, 1), out(KEY_A, 0), sync()]);
+        assert_eq!(translate(&evenger, vec![key(KEY_B, 1), key(KEY_B, 0)]),
+            vec![out(KEY_B, 1), out(KEY_C, 1), sync()]);
+        assert_eq!(translate(&evenger, vec![key(KEY_D, 1), key(KEY_D, 2)]),
+            vec![out(KEY_D, 1), out(KEY_E, 1), sync()]);
+        assert_eq!(translate(&evenger, vec![key(KEY_F, 1)]), vec![out(KEY_G, 1), sync()]);
+        assert_eq!(translate(&evenger, vec![key(KEY_F, 0)]), vec![out(KEY_G, 0), sync()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn taps_are_releases_with_no_press_in_between() {
+        let (evenger, path) = evenger("tap", "rule KEY_LEFTALT => on tap post tap KEY_LEFTMETA\n");
+        let meta = vec![out(KEY_LEFTALT, 0), out(KEY_LEFTMETA, 1), sync(), out(KEY_LEFTMETA, 0), sync()];
+
+        translate(&evenger, vec![key(KEY_LEFTALT, 1)]);
+        translate(&evenger, vec![key(KEY_LEFTALT, 2)]);
+        assert_eq!(translate(&evenger, vec![key(KEY_LEFTALT, 0)]), meta);
+
+        /* another key pressed in between, even on another device */
+        translate(&evenger, vec![key(KEY_LEFTALT, 1)]);
+        translate(&evenger, vec![event("mouse", EV_KEY, BTN_LEFT, 1)]);
+        assert_eq!(translate(&evenger, vec![key(KEY_LEFTALT, 0)]), vec![out(KEY_LEFTALT, 0), sync()]);
+
+        /* another key released in between doesn't count */
+        translate(&evenger, vec![key(KEY_LEFTALT, 1)]);
+        translate(&evenger, vec![event("mouse", EV_KEY, BTN_LEFT, 0)]);
+        assert_eq!(translate(&evenger, vec![key(KEY_LEFTALT, 0)]), meta);
+
+        /* nor does the same key on another device */
+        translate(&evenger, vec![key(KEY_LEFTALT, 1)]);
+        translate(&evenger, vec![event("other", EV_KEY, KEY_LEFTALT, 1)]);
+        assert_eq!(translate(&evenger, vec![key(KEY_LEFTALT, 0)]), vec![out(KEY_LEFTALT, 0), sync()]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn axes_take_their_keys() {
+        let (evenger, path) = evenger("axes", "gamepad pad\naxis pad:ABS_X KEY_A KEY_D\n");
+        assert_eq!(evenger.take_event(&key(KEY_A, 1)), vec!["axis pad:ABS_X".to_string()]);
+        assert_eq!(evenger.take_event(&key(KEY_D, 1)), vec!["axis pad:ABS_X".to_string()]);
+        assert!(evenger.take_event(&key(KEY_S, 1)).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn requests() {
+        let (mut evenger, path) = evenger("requests", "rule KEY_A if $app=firefox => drop\n");
+        let failure = |error: &str| json!({ "ok": false, "error": error });
+
+        assert_eq!(evenger.handle_request(&json!({})), failure("expected a `command`"));
+        assert_eq!(evenger.handle_request(&json!({ "command": "frobnicate" })),
+            failure("unknown command: frobnicate"));
+
+        assert_eq!(evenger.handle_request(&json!({ "command": "set", "name": "app", "value": "firefox" })),
+            json!({ "ok": true }));
+        assert_eq!(translate(&evenger, vec![key(KEY_A, 1)]), vec![]);
+        assert_eq!(evenger.handle_request(&json!({ "command": "set", "name": "app" })),
+            json!({ "ok": true }));
+        assert_eq!(translate(&evenger, vec![key(KEY_A, 1)]), vec![out(KEY_A, 1), sync()]);
+        assert_eq!(evenger.handle_request(&json!({ "command": "set", "name": "a b" })),
+            failure("invalid variable name: a b"));
+        assert_eq!(evenger.handle_request(&json!({ "command": "set", "name": "n", "value": 1.5 })),
+            failure("`value` must be a string, boolean or integer"));
+
+        assert_eq!(evenger.handle_request(&json!({ "command": "layer", "name": "nav" })),
+            json!({ "ok": true, "layer": "nav" }));
+        assert_eq!(evenger.handle_request(&json!({ "command": "layer" })),
+            json!({ "ok": true, "layer": null }));
+
+        evenger.destdev.start_capture();
+        assert_eq!(evenger.handle_request(&json!({ "command": "inject", "actions": ["tap KEY_B", "move REL_X 5"] })),
+            json!({ "ok": true, "actions": 4 }));
+        assert_eq!(evenger.destdev.take_capture(), vec![
+            out(KEY_B, 1), sync(), out(KEY_B, 0), (EventTarget::new(EV_REL, REL_X), 5), sync(),
+        ]);
+
+        assert_eq!(evenger.handle_request(&json!({ "command": "pause" })),
+            json!({ "ok": true, "paused": true }));
+        assert_eq!(evenger.handle_request(&json!({ "command": "inject", "actions": "tap KEY_B" })),
+            failure("remapping is paused"));
+        assert_eq!(evenger.handle_request(&json!({ "command": "resume" })),
+            json!({ "ok": true, "paused": false }));
+
+        assert_eq!(evenger.handle_request(&json!({ "command": "reload" })),
+            json!({ "ok": true, "rules": 1 }));
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -4,9 +4,18 @@ mod error;
 mod srcdev;
 mod destdev;
 mod rule;
+mod config;
+mod control;
+mod vars;
+mod transform;
+mod axis;
+mod stick;
 
-pub use evenger::Evenger;
+pub use evenger::{Evenger, Options};
 pub use error::Error;
+pub use config::Config;
+pub use control::request as control_request;
+pub use destdev::{SinkKind, is_virtual_device};
 
 pub type Result<T> = std::result::Result<T, Error>;
 pub type DeviceId = std::rc::Rc<String>;
@@ -1,20 +1,67 @@
-
+use crate::foreign::*;
 use super::{DeviceId};
 use super::srcdev::*;
 use super::destdev::*;
+use super::vars::Variables;
 use std::rc::Rc;
 
+/// Keys that `Condition::Only` expects to be released unless listed.
+pub const MODIFIER_KEYS: &[u32] = &[
+    KEY_LEFTCTRL, KEY_RIGHTCTRL, KEY_LEFTSHIFT, KEY_RIGHTSHIFT,
+    KEY_LEFTALT, KEY_RIGHTALT, KEY_LEFTMETA, KEY_RIGHTMETA,
+];
+
 pub struct RuleSet {
     rules: Vec<Rc<Rule>>,
 }
 
 pub struct Rule {
+    name: String,
+    /// The rule as written, with whitespace normalized.
+    definition: String,
     device: Option<DeviceId>,
-    main: EventTarget,
-    modifiers: Box<[ModifierRule]>,
+    main: TargetSet,
+    trigger: Trigger,
+    condition: Condition,
     actions: Vec<ActionRule>,
 }
 
+/// The values of an event that a rule or an action reacts to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    Any,
+    Value(i32),
+    /// A key going down.
+    Press,
+    /// A key coming up.
+    Release,
+    /// A key autorepeating.
+    Repeat,
+    /// A key coming up with no other key pressed since it went down.
+    Tap,
+}
+
+/// Event codes of one type, in an order that lets actions map them to
+/// other codes by position.
+#[derive(Clone, PartialEq)]
+pub struct TargetSet {
+    type_: u32,
+    codes: Vec<u32>,
+}
+
+/// A condition on the state of the source devices and variables.
+#[derive(Clone, PartialEq)]
+pub enum Condition {
+    Modifier(ModifierRule),
+    Not(Box<Condition>),
+    /// Holds if all of the conditions hold, including if there are none.
+    All(Vec<Condition>),
+    /// Holds if any of the conditions holds.
+    Any(Vec<Condition>),
+    /// Holds if the keys are held, and no other modifier key is.
+    Only(Vec<ModifierRule>),
+}
+
 #[derive(Clone, PartialEq)]
 pub struct ModifierRule {
     device: Option<DeviceId>,
@@ -24,13 +71,31 @@ pub struct ModifierRule {
 #[derive(Clone)]
 pub struct ActionRule {
     phase: ActionRulePhase,
+    trigger: Trigger,
     action: Action,
+    code: CodeArg,
 }
 
-#[derive(Clone)]
+/// The code an action applies to.
+#[derive(Clone, PartialEq)]
+pub enum CodeArg {
+    /// The code given in the action itself.
+    Fixed,
+    /// The code of the triggering event.
+    Matched,
+    /// The code at the position of the triggering event's code in the
+    /// rule's targets.
+    Mapped(Vec<u32>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum ActionRulePhase {
+    /// Performed before the event is passed through or replaced.
     PreAction,
+    /// Replaces the event. The event is passed through if no matching rule
+    /// has a peri-action.
     PeriAction,
+    /// Performed after the event is passed through or replaced.
     PostAction,
 }
 
@@ -42,6 +107,14 @@ impl RuleSet {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(Rc::new(rule));
+    }
+
     pub fn insert_rule(&mut self,
         device: Option<DeviceId>,
         main: EventTarget,
@@ -49,26 +122,50 @@ impl RuleSet {
         phase: ActionRulePhase,
         action: Action,
     ) {
-        self.get_or_create_rule_mut(device, main, modifiers)
+        let condition = Condition::All(modifiers.iter()
+            .cloned()
+            .map(Condition::Modifier)
+            .collect());
+        self.get_or_create_rule_mut(device, TargetSet::single(main), condition)
             .add_action(ActionRule::new(phase, action));
     }
 
-    pub fn match_rules(&self) {
-        // TODO: implement this
+    /// Returns the rules triggered by `event`, in the order they were added.
+    pub fn match_rules(&self, event: &Event, srcdevs: &SourceDeviceSet, vars: &Variables) -> Vec<Rc<Rule>> {
+        self.rules.iter()
+            .filter(|rule| rule.matches(event, srcdevs, vars))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Rc<Rule>> {
+        self.rules.iter().find(|rule| rule.name == name)
     }
 
-    fn get_or_create_rule_mut(&mut self, device: Option<DeviceId>, main: EventTarget, modifiers: &[ModifierRule]) -> &mut Rule {
+    /// Returns whether a rule of the same name and definition as `rule` is
+    /// in the set.
+    pub fn contains(&self, rule: &Rule) -> bool {
+        self.rules.iter()
+            .any(|other| other.name == rule.name && other.definition == rule.definition)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rc<Rule>> {
+        self.rules.iter()
+    }
+
+    fn get_or_create_rule_mut(&mut self, device: Option<DeviceId>, main: TargetSet, condition: Condition) -> &mut Rule {
         let idx: Option<usize> = self.rules.iter()
             .position(|rule: &Rc<Rule>|
                 rule.device == device &&
                 rule.main == main &&
-                &*rule.modifiers == modifiers
+                rule.trigger == Trigger::Any &&
+                rule.condition == condition
             );
 
         let idx = match idx {
             Some(v) => v,
             None => {
-                let rule = Rule::new(device, main, modifiers);
+                let rule = Rule::new(device, main, condition);
                 self.rules.push(Rc::new(rule));
                 self.rules.len() - 1
             }
@@ -81,27 +178,228 @@ impl RuleSet {
 impl Rule {
     pub fn new(
         device: Option<DeviceId>,
-        main: EventTarget,
-        modifiers: &[ModifierRule],
+        main: TargetSet,
+        condition: Condition,
     ) -> Self {
         Self {
+            name: String::new(),
+            definition: String::new(),
             device,
             main,
-            modifiers: Vec::from(modifiers).into_boxed_slice(),
+            trigger: Trigger::Any,
+            condition,
             actions: Vec::new(),
         }
     }
 
-    fn add_action(&mut self, action: ActionRule) {
+    /// Names the rule, which identifies it across reloads together with
+    /// its definition. Unnamed rules are told apart by definition alone.
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Records how the rule was written, to tell whether a rule changed.
+    pub fn with_definition<S: Into<String>>(mut self, definition: S) -> Self {
+        self.definition = definition.into();
+        self
+    }
+
+    /// Restricts the rule to events matching `trigger`.
+    pub fn with_trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    pub fn definition(&self) -> &str {
+        &self.definition
+    }
+
+    pub fn device(&self) -> Option<DeviceId> {
+        self.device.clone()
+    }
+
+    pub fn main(&self) -> &TargetSet {
+        &self.main
+    }
+
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    /// Returns the actions of `phase` that `event` triggers, applied to the
+    /// codes they take from it.
+    pub fn actions<'a>(&'a self, phase: ActionRulePhase, event: &'a Event) -> impl Iterator<Item = Action> + 'a {
+        let code = event.target().code();
+        let index = self.main.position(event.target());
+
+        self.actions.iter()
+            .filter(move |rule| rule.phase == phase && rule.trigger.test(event))
+            .filter_map(move |rule| match &rule.code {
+                CodeArg::Fixed => Some(rule.action.clone()),
+                CodeArg::Matched => Some(rule.action.with_code(code)),
+                CodeArg::Mapped(codes) => index
+                    .and_then(|index| codes.get(index))
+                    .map(|&code| rule.action.with_code(code)),
+            })
+    }
+
+    pub fn add_action(&mut self, action: ActionRule) {
         self.actions.push(action);
     }
+
+    pub fn matches(&self, event: &Event, srcdevs: &SourceDeviceSet, vars: &Variables) -> bool {
+        if let Some(device) = &self.device {
+            if *device != event.srcdev_id() {
+                return false;
+            }
+        }
+
+        if self.main.position(event.target()).is_none() {
+            return false;
+        }
+
+        if !self.trigger.test(event) {
+            return false;
+        }
+
+        self.condition.test(srcdevs, vars)
+    }
+}
+
+impl Trigger {
+    pub fn test(self, event: &Event) -> bool {
+        match self {
+            Trigger::Any => true,
+            Trigger::Value(value) => event.value() == value,
+            Trigger::Press => event.value() == 1,
+            Trigger::Release => event.value() == 0,
+            Trigger::Repeat => event.value() == 2,
+            Trigger::Tap => event.is_tap(),
+        }
+    }
+}
+
+impl TargetSet {
+    pub fn new(type_: u32, codes: Vec<u32>) -> Self {
+        Self {
+            type_,
+            codes,
+        }
+    }
+
+    pub fn single(target: EventTarget) -> Self {
+        Self::new(target.type_(), vec![target.code()])
+    }
+
+    pub fn type_(&self) -> u32 {
+        self.type_
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn position(&self, target: EventTarget) -> Option<usize> {
+        match target.type_() == self.type_ {
+            true => self.codes.iter().position(|&code| code == target.code()),
+            false => None,
+        }
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item = EventTarget> + '_ {
+        self.codes.iter().map(move |&code| EventTarget::new(self.type_, code))
+    }
+}
+
+impl Condition {
+    pub fn test(&self, srcdevs: &SourceDeviceSet, vars: &Variables) -> bool {
+        match self {
+            Condition::Modifier(modf) => modf.test(srcdevs, vars),
+            Condition::Not(cond) => !cond.test(srcdevs, vars),
+            Condition::All(conds) => conds.iter().all(|cond| cond.test(srcdevs, vars)),
+            Condition::Any(conds) => conds.iter().any(|cond| cond.test(srcdevs, vars)),
+            Condition::Only(keys) => {
+                let listed = |code: u32| keys.iter()
+                    .any(|key| key.target() == Some(EventTarget::new(EV_KEY, code)));
+
+                keys.iter().all(|key| key.test(srcdevs, vars))
+                    && MODIFIER_KEYS.iter()
+                        .filter(|&&code| !listed(code))
+                        .all(|&code| !srcdevs.test_modifier(None, Modifier::Key(code, true)))
+            },
+        }
+    }
+
+    /// Returns every modifier the condition refers to.
+    pub fn modifiers(&self) -> Vec<&ModifierRule> {
+        match self {
+            Condition::Modifier(modf) => vec![modf],
+            Condition::Not(cond) => cond.modifiers(),
+            Condition::All(conds) | Condition::Any(conds) => conds.iter()
+                .flat_map(Condition::modifiers)
+                .collect(),
+            Condition::Only(keys) => keys.iter().collect(),
+        }
+    }
+}
+
+impl ModifierRule {
+    pub fn new(device: Option<DeviceId>, target: Modifier) -> Self {
+        Self {
+            device,
+            target,
+        }
+    }
+
+    pub fn device(&self) -> Option<DeviceId> {
+        self.device.clone()
+    }
+
+    pub fn modifier(&self) -> &Modifier {
+        &self.target
+    }
+
+    pub fn target(&self) -> Option<EventTarget> {
+        self.target.target()
+    }
+
+    pub fn test(&self, srcdevs: &SourceDeviceSet, vars: &Variables) -> bool {
+        match &self.target {
+            Modifier::Var(name, value, state) => vars.test(name, value.as_deref()) == *state,
+            modf => srcdevs.test_modifier(self.device.clone(), modf.clone()),
+        }
+    }
 }
 
 impl ActionRule {
     pub fn new(phase: ActionRulePhase, action: Action) -> Self {
         Self {
             phase,
+            trigger: Trigger::Any,
             action,
+            code: CodeArg::Fixed,
+        }
+    }
+
+    /// Restricts the action to events matching `trigger`.
+    pub fn with_trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    /// Makes the action take its code from the triggering event.
+    pub fn with_code(mut self, code: CodeArg) -> Self {
+        self.code = code;
+        self
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name.is_empty() {
+            true => write!(fmt, "`{}`", self.definition),
+            false => write!(fmt, "[{}] `{}`", self.name, self.definition),
         }
     }
 }
@@ -1,8 +1,9 @@
 
-use crate::evdev::{Device, InputEvent, ReadFlag, ReadStatus};
+use crate::evdev::{self, Device, InputEvent, ReadFlag, ReadStatus};
 use crate::foreign::*;
-use super::{DeviceId, Result};
-use std::{path::Path, rc::Rc, rc::Weak};
+use super::{DeviceId, Error, Result};
+use std::{path::Path, path::PathBuf, rc::Rc, rc::Weak};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::os::unix::io::RawFd;
 
@@ -14,15 +15,33 @@ pub struct SourceDeviceSet {
 
 pub struct SourceDevice {
     id: DeviceId,
+    path: PathBuf,
     dev: Device,
+    /// Events of the frame being read, up to the next `SYN_REPORT`.
+    pending: RefCell<Vec<Event>>,
+    /// Whether the device is being resynchronized after `SYN_DROPPED`.
+    syncing: Cell<bool>,
+}
+
+/// Events reported together, up to a `SYN_REPORT`.
+pub struct Frame {
+    pub events: Vec<Event>,
+    /// Whether the frame was synthesized by libevdev after `SYN_DROPPED`,
+    /// describing the changes to the device state since the last frame.
+    pub resync: bool,
 }
 
 pub struct Event {
     srcdev_id: DeviceId,
     base: InputEvent,
+    /// For absolute axes, the value scaled to -1..1 over the axis range.
+    position: Option<f32>,
+    /// Whether the event releases a key that was pressed with no other key
+    /// pressed in between.
+    tap: bool,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct EventTarget(u32, u32);
 
 #[derive(Clone, PartialEq)]
@@ -31,6 +50,8 @@ pub enum Modifier {
     // TODO: Abs(u32), // min/max/resoultin? multitouch?
     Led(u32, bool),
     Switch(u32, bool),
+    /// A variable, compared to a value or tested for being true.
+    Var(String, Option<String>, bool),
 }
 
 
@@ -69,6 +90,21 @@ impl SourceDeviceSet {
         self.fdmap.get(&fd).map(|rc| rc.clone())
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Rc<SourceDevice>> {
+        self.fdmap.values()
+    }
+
+    pub fn clear(&mut self) {
+        self.fdmap.clear();
+        self.idmap.clear();
+    }
+
+    /// Returns whether all of `keys` are held down, each on any device.
+    pub fn keys_held(&self, keys: &[u32]) -> bool {
+        keys.iter().all(|&code| self.fdmap.values()
+            .any(|srcdev| srcdev.get_event_state(EV_KEY, code).unwrap_or(0) != 0))
+    }
+
     pub fn test_modifier(&self, device: Option<DeviceId>, modf: Modifier) -> bool {
         match device {
             Some(id) => self.get_by_id(id)
@@ -85,46 +121,170 @@ impl SourceDeviceSet {
 }
 
 impl SourceDevice {
-    pub fn open<P: AsRef<Path>>(id: DeviceId, devpath: P) -> Result<SourceDevice> {
+    pub fn open<P: AsRef<Path>>(id: DeviceId, devpath: P, grab: bool) -> Result<SourceDevice> {
         use nix::fcntl::OFlag;
         use nix::sys::stat::Mode;
+        let path = devpath.as_ref().to_path_buf();
         let fd = nix::fcntl::open(
-            devpath.as_ref(),
+            &path,
             OFlag::O_CLOEXEC | OFlag::O_NONBLOCK,
             Mode::empty()
-        )?;
+        ).map_err(|e| Error::device(&id, &path,
+            e.as_errno().unwrap_or(nix::errno::Errno::UnknownErrno)))?;
+
+        let dev = match Device::new_from_fd(fd) {
+            Ok(dev) => dev,
+            Err(errno) => {
+                let _ = nix::unistd::close(fd);
+                return Err(Error::device(&id, &path, errno));
+            },
+        };
 
-        let dev = Device::new_from_fd(fd)?;
+        let srcdev = SourceDevice {
+            id,
+            path,
+            dev,
+            pending: RefCell::new(Vec::new()),
+            syncing: Cell::new(false),
+        };
 
-        if let Err(e) = dev.grab(true) {
-            eprintln!("cannot grab device {}: {}", devpath.as_ref().to_string_lossy(), e);
+        if grab {
+            if let Err(e) = srcdev.grab(true) {
+                warn!("{}", e);
+            }
         }
 
-        Ok(SourceDevice { id, dev })
+        Ok(srcdev)
+    }
+
+    /// Grabs the device exclusively, or releases the grab.
+    pub fn grab(&self, grab: bool) -> Result<()> {
+        self.dev.grab(grab)
+            .map_err(|errno| Error::GrabFailed {
+                device: self.id(),
+                path: self.path.clone(),
+                errno,
+            })
     }
 
     pub fn id(&self) -> DeviceId {
         Rc::clone(&self.id)
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn fd(&self) -> RawFd {
         self.dev.fd()
             .expect("SourceDevice should be backed by an actual file")
     }
 
-    pub fn read_event(&self) -> Result<Option<Event>> {
+    /// Reads the next event. After `SYN_DROPPED`, the events describing
+    /// the state changes missed in the meantime are read first.
+    /// `next` reads from the device, as `Device::next_event` does.
+    fn read_event<F>(&self, next: &mut F) -> Result<Option<Event>>
+        where F: FnMut(ReadFlag) -> std::result::Result<ReadStatus, nix::errno::Errno>
+    {
         loop {
-            match self.dev.next_event(ReadFlag::Normal)? {
+            let flag = match self.syncing.get() {
+                true => ReadFlag::Sync,
+                false => ReadFlag::Normal,
+            };
+
+            let status = next(flag)
+                .map_err(|errno| Error::device(&self.id, &self.path, errno))?;
+            match status {
                 ReadStatus::Success(ev) => {
-                    let event = Event::new(self.id(), ev);
-                    return Ok(Some(event))
+                    return Ok(Some(self.event(ev)))
+                },
+                ReadStatus::Sync(ev) => {
+                    if self.syncing.get() {
+                        return Ok(Some(self.event(ev)))
+                    }
+
+                    /* SYN_DROPPED: the incomplete frame is lost */
+                    warn!("{}: events dropped, resynchronizing", self.id);
+                    self.pending.borrow_mut().clear();
+                    self.syncing.set(true);
+                },
+                ReadStatus::TryAgain => {
+                    if !self.syncing.get() {
+                        return Ok(None)
+                    }
+                    self.syncing.set(false);
                 },
-                ReadStatus::Sync(_) => continue,
-                ReadStatus::TryAgain => return Ok(None),
             }
         }
     }
 
+    fn event(&self, ev: InputEvent) -> Event {
+        let mut event = Event::new(self.id(), ev);
+        if event.target().type_() == EV_ABS {
+            event.position = self.abs_position(event.target().code(), event.value());
+        }
+        event
+    }
+
+    /// Scales a value of an absolute axis to -1..1 over the axis range, with
+    /// the center of the range at 0. Values within the flat area around the
+    /// center the device reports count as centered.
+    pub fn abs_position(&self, code: u32, value: i32) -> Option<f32> {
+        let info = self.dev.abs_info(code)?;
+        if info.maximum <= info.minimum {
+            return None;
+        }
+
+        let center = (info.minimum as f32 + info.maximum as f32) / 2.0;
+        let offset = value as f32 - center;
+        if offset.abs() <= info.flat as f32 {
+            return Some(0.0);
+        }
+        let half = (info.maximum as f32 - info.minimum as f32) / 2.0;
+        Some((offset / half).clamp(-1.0, 1.0))
+    }
+
+    /// Reads events up to the next `SYN_REPORT` and returns them, excluding
+    /// the `SYN_REPORT` itself. Returns `None` if no complete frame is
+    /// available yet; the events read so far are kept for the next call.
+    pub fn read_frame(&self) -> Result<Option<Frame>> {
+        self.read_frame_from(&mut |flag| self.dev.next_event(flag))
+    }
+
+    fn read_frame_from<F>(&self, next: &mut F) -> Result<Option<Frame>>
+        where F: FnMut(ReadFlag) -> std::result::Result<ReadStatus, nix::errno::Errno>
+    {
+        while let Some(event) = self.read_event(next)? {
+            let target = event.target();
+            if (target.type_(), target.code()) == (EV_SYN, SYN_REPORT) {
+                return Ok(Some(Frame {
+                    events: self.pending.replace(Vec::new()),
+                    resync: self.syncing.get(),
+                }));
+            }
+            self.pending.borrow_mut().push(event);
+        }
+
+        Ok(None)
+    }
+
+    /// Returns whether a key or button of the device is held down.
+    pub fn is_pressed(&self, code: u32) -> bool {
+        self.get_event_state(EV_KEY, code).unwrap_or(0) != 0
+    }
+
+    /// Returns whether the device can report events of `target`.
+    pub fn supports(&self, target: EventTarget) -> bool {
+        self.dev.has_event_code(target.type_(), target.code())
+    }
+
+    /// Returns the keys and buttons of the device that are held down.
+    pub fn pressed_keys(&self) -> Vec<u32> {
+        (0..KEY_CNT)
+            .filter(|&code| self.is_pressed(code))
+            .collect()
+    }
+
     // TODO: specilaized functions: get_key_state, get_sw_state, etc
     pub fn get_event_state(&self, type_: u32, code: u32) -> Option<i32> {
         self.dev.fetch_event_value(type_, code)
@@ -148,9 +308,21 @@ impl SourceDevice {
     }
 }
 
+impl Modifier {
+    /// Returns the event whose state the modifier depends on, if any.
+    pub fn target(&self) -> Option<EventTarget> {
+        match *self {
+            Modifier::Key(code, _)    => Some(EventTarget::new(EV_KEY, code)),
+            Modifier::Led(code, _)    => Some(EventTarget::new(EV_LED, code)),
+            Modifier::Switch(code, _) => Some(EventTarget::new(EV_SW , code)),
+            Modifier::Var(..)         => None,
+        }
+    }
+}
+
 impl Event {
     pub fn new(srcdev_id: DeviceId, base: InputEvent) -> Self {
-        Self { srcdev_id, base }
+        Self { srcdev_id, base, position: None, tap: false }
     }
 
     pub fn srcdev_id(&self) -> DeviceId {
@@ -164,6 +336,18 @@ impl Event {
     pub fn value(&self) -> i32 {
         self.base.value()
     }
+
+    pub fn position(&self) -> Option<f32> {
+        self.position
+    }
+
+    pub fn is_tap(&self) -> bool {
+        self.tap
+    }
+
+    pub fn set_tap(&mut self, tap: bool) {
+        self.tap = tap;
+    }
 }
 
 impl EventTarget {
@@ -173,3 +357,110 @@ impl EventTarget {
     pub fn type_(&self) -> u32 { self.0 }
     pub fn code(&self) -> u32 { self.1 }
 }
+
+impl std::fmt::Display for EventTarget {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match evdev::event_type_name(self.0) {
+            Some(name) => write!(fmt, "{}", name)?,
+            None => write!(fmt, "{}", self.0)?,
+        }
+        match evdev::event_code_name(self.0, self.1) {
+            Some(name) => write!(fmt, " {}", name),
+            None => write!(fmt, " {}", self.1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn srcdev() -> SourceDevice {
+        SourceDevice {
+            id: Rc::new("kbd".to_string()),
+            path: PathBuf::from("/dev/input/event0"),
+            dev: Device::new().unwrap(),
+            pending: RefCell::new(Vec::new()),
+            syncing: Cell::new(false),
+        }
+    }
+
+    fn key(code: u32, value: i32) -> InputEvent {
+        InputEvent::new(EV_KEY, code, value)
+    }
+
+    fn report() -> InputEvent {
+        InputEvent::new(EV_SYN, SYN_REPORT, 0)
+    }
+
+    /// Reads a frame off `statuses`, checking that each is read with the
+    /// flag it comes with.
+    fn read(srcdev: &SourceDevice, statuses: Vec<(ReadFlag, ReadStatus)>) -> Option<Frame> {
+        let mut statuses: VecDeque<_> = statuses.into();
+        let frame = srcdev.read_frame_from(&mut |flag| {
+            let (expected, status) = statuses.pop_front().expect("read too far");
+            assert_eq!(flag as i32, expected as i32);
+            Ok(status)
+        }).unwrap();
+        assert!(statuses.is_empty(), "stopped reading early");
+        frame
+    }
+
+    fn events(frame: &Frame) -> Vec<(u32, u32, i32)> {
+        frame.events.iter()
+            .map(|event| (event.target().type_(), event.target().code(), event.value()))
+            .collect()
+    }
+
+    #[test]
+    fn frames_end_at_syn_report() {
+        use ReadFlag::Normal;
+        let srcdev = srcdev();
+
+        let frame = read(&srcdev, vec![
+            (Normal, ReadStatus::Success(key(KEY_A, 1))),
+            (Normal, ReadStatus::Success(key(KEY_B, 1))),
+            (Normal, ReadStatus::Success(report())),
+        ]).unwrap();
+        assert_eq!(events(&frame), vec![(EV_KEY, KEY_A, 1), (EV_KEY, KEY_B, 1)]);
+        assert!(!frame.resync);
+
+        /* an incomplete frame is kept for the next read */
+        assert!(read(&srcdev, vec![
+            (Normal, ReadStatus::Success(key(KEY_A, 0))),
+            (Normal, ReadStatus::TryAgain),
+        ]).is_none());
+        let frame = read(&srcdev, vec![
+            (Normal, ReadStatus::Success(key(KEY_B, 0))),
+            (Normal, ReadStatus::Success(report())),
+        ]).unwrap();
+        assert_eq!(events(&frame), vec![(EV_KEY, KEY_A, 0), (EV_KEY, KEY_B, 0)]);
+    }
+
+    #[test]
+    fn syn_dropped_resynchronizes() {
+        use ReadFlag::{Normal, Sync};
+        let srcdev = srcdev();
+
+        /* the incomplete frame is lost, and the sync events make a frame
+         * of their own */
+        let frame = read(&srcdev, vec![
+            (Normal, ReadStatus::Success(key(KEY_A, 1))),
+            (Normal, ReadStatus::Sync(InputEvent::new(EV_SYN, SYN_DROPPED, 0))),
+            (Sync, ReadStatus::Sync(key(KEY_B, 1))),
+            (Sync, ReadStatus::Sync(report())),
+        ]).unwrap();
+        assert_eq!(events(&frame), vec![(EV_KEY, KEY_B, 1)]);
+        assert!(frame.resync);
+
+        /* reading goes back to normal once the sync events are through */
+        let frame = read(&srcdev, vec![
+            (Sync, ReadStatus::TryAgain),
+            (Normal, ReadStatus::Success(key(KEY_C, 1))),
+            (Normal, ReadStatus::Success(report())),
+        ]).unwrap();
+        assert_eq!(events(&frame), vec![(EV_KEY, KEY_C, 1)]);
+        assert!(!frame.resync);
+    }
+}
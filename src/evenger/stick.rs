@@ -0,0 +1,160 @@
+//! Analog sticks moving the pointer or scrolling. Stick positions are
+//! levels rather than deltas, so they are turned into movement over time.
+
+use crate::evdev;
+use crate::foreign::*;
+use super::DeviceId;
+use super::srcdev::Event;
+use super::transform::Transform;
+use std::time::Instant;
+
+#[derive(Clone, PartialEq)]
+pub struct StickConfig {
+    pub device: DeviceId,
+    /// The absolute axis of the stick.
+    pub code: u32,
+    /// The relative axis of the output it moves.
+    pub output: u32,
+    /// Units per second when the stick is pushed all the way.
+    pub speed: f32,
+    /// Applied to the stick position, from -1 to 1, before scaling it by
+    /// `speed`, e.g. for a dead zone or a curve.
+    pub transform: Transform,
+}
+
+impl std::fmt::Display for StickConfig {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match evdev::event_code_name(EV_ABS, self.code) {
+            Some(name) => write!(fmt, "{}:{}", self.device, name),
+            None => write!(fmt, "{}:{}", self.device, self.code),
+        }
+    }
+}
+
+impl std::fmt::Display for Stick {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.config.fmt(fmt)
+    }
+}
+
+pub struct Stick {
+    config: StickConfig,
+    /// Whether the stick was out of its dead zone at the last update.
+    moving: bool,
+    updated: Instant,
+}
+
+impl Stick {
+    pub fn new(config: StickConfig) -> Self {
+        Self {
+            config,
+            moving: false,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn device(&self) -> &DeviceId {
+        &self.config.device
+    }
+
+    pub fn code(&self) -> u32 {
+        self.config.code
+    }
+
+    pub fn output(&self) -> u32 {
+        self.config.output
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+
+    /// Returns whether `event` comes from the stick.
+    pub fn handles(&self, event: &Event) -> bool {
+        let target = event.target();
+        target.type_() == EV_ABS
+            && target.code() == self.config.code
+            && event.srcdev_id() == self.config.device
+    }
+
+    /// Returns how far to move for the time passed since the last update,
+    /// with the stick at `position`. Movement starts with the update after
+    /// the stick left its dead zone, so that the time it rested there does
+    /// not count.
+    pub fn update(&mut self, position: f32, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = now;
+
+        let velocity = self.config.transform.apply(position) * self.config.speed;
+        let distance = match self.moving {
+            true => velocity * elapsed.as_secs_f32(),
+            false => 0.0,
+        };
+        self.moving = velocity != 0.0;
+
+        distance
+    }
+
+    /// Stops the stick until it is updated again.
+    pub fn stop(&mut self) {
+        self.moving = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::transform::Step;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    fn stick() -> Stick {
+        Stick::new(StickConfig {
+            device: Rc::new("gamepad".to_string()),
+            code: ABS_X,
+            output: REL_X,
+            speed: 1000.0,
+            transform: Transform::new(vec![Step::DeadZone(0.15)]),
+        })
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    fn assert_near(distance: f32, expected: f32) {
+        assert!((distance - expected).abs() < 1e-3, "{} is not {}", distance, expected);
+    }
+
+    #[test]
+    fn moves_by_speed_over_time() {
+        let mut stick = stick();
+        let start = Instant::now();
+
+        /* the time before the stick left its dead zone doesn't count */
+        assert_eq!(stick.update(1.0, ms(start, 1000)), 0.0);
+        assert!(stick.is_moving());
+        assert_near(stick.update(1.0, ms(start, 1500)), 500.0);
+        assert_near(stick.update(-0.5, ms(start, 1600)), -50.0);
+
+        /* nor does the time since it returned to it */
+        assert_eq!(stick.update(0.1, ms(start, 1700)), 0.0);
+        assert!(!stick.is_moving());
+        assert_eq!(stick.update(1.0, ms(start, 2700)), 0.0);
+        assert_near(stick.update(1.0, ms(start, 2710)), 10.0);
+    }
+
+    #[test]
+    fn stops() {
+        let mut stick = stick();
+        let start = Instant::now();
+        stick.update(1.0, start);
+
+        stick.stop();
+        assert_eq!(stick.update(1.0, ms(start, 100)), 0.0);
+        assert_near(stick.update(1.0, ms(start, 200)), 100.0);
+
+        /* time going backwards moves nothing */
+        assert_eq!(stick.update(1.0, ms(start, 150)), 0.0);
+    }
+}
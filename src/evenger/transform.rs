@@ -0,0 +1,142 @@
+//! Transformations of event values, applied by `value` actions before the
+//! result is written to the output.
+
+/// A chain of steps, applied in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transform {
+    steps: Vec<Step>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    /// Multiplies the value.
+    Scale(f32),
+    /// Adds to the value.
+    Offset(f32),
+    /// Limits the value to a range.
+    Clamp(f32, f32),
+    /// Turns values closer to zero than the radius into zero.
+    DeadZone(f32),
+    /// Maps the value through a piecewise linear curve, given as points
+    /// sorted by input. Values beyond the ends map to the end points.
+    Curve(Vec<(f32, f32)>),
+    /// Turns the value into 1 if it reaches the threshold, away from zero,
+    /// and into 0 otherwise.
+    Threshold(f32),
+}
+
+impl Transform {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self {
+            steps,
+        }
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        self.steps.iter().fold(value, |value, step| step.apply(value))
+    }
+}
+
+impl Step {
+    pub fn apply(&self, value: f32) -> f32 {
+        match self {
+            Step::Scale(factor) => value * factor,
+            Step::Offset(offset) => value + offset,
+            Step::Clamp(min, max) => value.max(*min).min(*max),
+            Step::DeadZone(radius) => match value.abs() < *radius {
+                true => 0.0,
+                false => value,
+            },
+            Step::Curve(points) => interpolate(points, value),
+            Step::Threshold(threshold) => {
+                let reached = match *threshold < 0.0 {
+                    true => value <= *threshold,
+                    false => value >= *threshold,
+                };
+                if reached { 1.0 } else { 0.0 }
+            },
+        }
+    }
+}
+
+fn interpolate(points: &[(f32, f32)], value: f32) -> f32 {
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return value,
+    };
+    if value <= first.0 {
+        return first.1;
+    }
+    if value >= last.0 {
+        return last.1;
+    }
+
+    points.windows(2)
+        .find(|pair| value <= pair[1].0)
+        .map(|pair| {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            match x1 > x0 {
+                true => y0 + (value - x0) * (y1 - y0) / (x1 - x0),
+                false => y1,
+            }
+        })
+        .unwrap_or(last.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_ends() {
+        let curve = Step::Curve(vec![(-10.0, -1.0), (0.0, 0.0), (10.0, 5.0)]);
+        assert_eq!(curve.apply(-10.0), -1.0);
+        assert_eq!(curve.apply(-50.0), -1.0);
+        assert_eq!(curve.apply(10.0), 5.0);
+        assert_eq!(curve.apply(50.0), 5.0);
+        assert_eq!(curve.apply(4.0), 2.0);
+        assert_eq!(curve.apply(-5.0), -0.5);
+    }
+
+    #[test]
+    fn curve_with_duplicate_inputs_jumps() {
+        let curve = Step::Curve(vec![(0.0, 0.0), (1.0, 0.0), (1.0, 10.0), (2.0, 10.0)]);
+        assert_eq!(curve.apply(0.5), 0.0);
+        assert_eq!(curve.apply(1.0), 0.0);
+        assert_eq!(curve.apply(1.5), 10.0);
+
+        let curve = Step::Curve(vec![(0.0, 0.0), (0.0, 4.0), (1.0, 8.0)]);
+        assert_eq!(curve.apply(0.0), 0.0);
+        assert_eq!(curve.apply(0.5), 6.0);
+    }
+
+    #[test]
+    fn negative_threshold() {
+        let threshold = Step::Threshold(-64.0);
+        assert_eq!(threshold.apply(-64.0), 1.0);
+        assert_eq!(threshold.apply(-100.0), 1.0);
+        assert_eq!(threshold.apply(-63.0), 0.0);
+        assert_eq!(threshold.apply(100.0), 0.0);
+
+        let threshold = Step::Threshold(64.0);
+        assert_eq!(threshold.apply(64.0), 1.0);
+        assert_eq!(threshold.apply(-100.0), 0.0);
+    }
+
+    #[test]
+    fn deadzone_keeps_its_radius() {
+        let deadzone = Step::DeadZone(8.0);
+        assert_eq!(deadzone.apply(7.9), 0.0);
+        assert_eq!(deadzone.apply(-7.9), 0.0);
+        assert_eq!(deadzone.apply(8.0), 8.0);
+        assert_eq!(deadzone.apply(-8.0), -8.0);
+    }
+
+    #[test]
+    fn steps_apply_in_order() {
+        let transform = Transform::new(vec![Step::Offset(10.0), Step::Scale(2.0), Step::Clamp(0.0, 30.0)]);
+        assert_eq!(transform.apply(0.0), 20.0);
+        assert_eq!(transform.apply(10.0), 30.0);
+        assert_eq!(transform.apply(-20.0), 0.0);
+    }
+}
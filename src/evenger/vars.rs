@@ -0,0 +1,80 @@
+//! Variables set from outside evenger, e.g. by a window manager hook
+//! reporting the focused application, which rules can depend on.
+
+use std::collections::HashMap;
+
+/// The variable the `layer` control request switches, for rules to test.
+pub const LAYER: &str = "layer";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VarValue {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Default)]
+pub struct Variables {
+    values: HashMap<String, VarValue>,
+}
+
+impl VarValue {
+    /// Returns whether the value counts as set: `true`, non-zero or
+    /// non-empty.
+    pub fn is_true(&self) -> bool {
+        match self {
+            VarValue::Bool(value) => *value,
+            VarValue::Int(value) => *value != 0,
+            VarValue::Str(value) => !value.is_empty(),
+        }
+    }
+}
+
+impl std::fmt::Display for VarValue {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VarValue::Bool(value) => value.fmt(fmt),
+            VarValue::Int(value) => value.fmt(fmt),
+            VarValue::Str(value) => value.fmt(fmt),
+        }
+    }
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&VarValue> {
+        self.values.get(name)
+    }
+
+    /// Sets a variable, or unsets it if `value` is `None`.
+    pub fn set(&mut self, name: &str, value: Option<VarValue>) {
+        match value {
+            Some(value) => self.values.insert(name.to_string(), value),
+            None => self.values.remove(name),
+        };
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &VarValue)> {
+        self.values.iter()
+    }
+
+    /// Tests a variable against `expected`, comparing their textual forms,
+    /// or tests whether it is true if `expected` is `None`. Unset variables
+    /// match nothing.
+    pub fn test(&self, name: &str, expected: Option<&str>) -> bool {
+        match (self.get(name), expected) {
+            (Some(value), Some(expected)) => value.to_string() == expected,
+            (Some(value), None) => value.is_true(),
+            (None, _) => false,
+        }
+    }
+}
+
+/// Returns whether `name` can name a variable.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
@@ -0,0 +1,277 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const CRATE_PREFIX: &str = concat!(env!("CARGO_PKG_NAME"), "::");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+pub enum Target {
+    Stderr,
+    File(File),
+    Journal(UnixDatagram),
+}
+
+pub struct Logger {
+    /// Level used for modules without a filter. `None` disables logging.
+    default: Option<Level>,
+    /// Per-module levels, matched by module path prefix.
+    filters: Vec<(String, Option<Level>)>,
+    timestamps: bool,
+    target: Target,
+}
+
+thread_local! {
+    static LOGGER: RefCell<Logger> = RefCell::new(Logger::new());
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!($crate::log::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+/// Replaces the logger of the current thread.
+pub fn init(logger: Logger) {
+    LOGGER.with(|cell| *cell.borrow_mut() = logger);
+}
+
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+    LOGGER.with(|cell| {
+        let mut logger = cell.borrow_mut();
+        if logger.enabled(level, module) {
+            logger.write(level, module, args);
+        }
+    });
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn  => "warn",
+            Level::Info  => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// Syslog priority of the level, as understood by the journal.
+    fn priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn  => 4,
+            Level::Info  => 6,
+            Level::Debug => 7,
+            Level::Trace => 7,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("invalid log level: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(fmt)
+    }
+}
+
+impl Target {
+    pub fn file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Target::File(file))
+    }
+
+    pub fn journal() -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(Target::Journal(socket))
+    }
+}
+
+impl Logger {
+    pub fn new() -> Self {
+        Logger {
+            default: Some(Level::Info),
+            filters: Vec::new(),
+            timestamps: false,
+            target: Target::Stderr,
+        }
+    }
+
+    /// Applies a comma-separated filter specification, such as
+    /// `warn,evenger::srcdev=debug,muxer=off`. An entry without a module
+    /// sets the default level.
+    pub fn parse_filters(&mut self, spec: &str) -> Result<(), String> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (module, level) = match entry.find('=') {
+                Some(i) => (Some(&entry[..i]), &entry[i + 1..]),
+                None => (None, entry),
+            };
+
+            let level = match level {
+                "off" | "none" => None,
+                level => Some(level.parse::<Level>()?),
+            };
+
+            match module {
+                Some(module) => {
+                    self.filters.retain(|(m, _)| m != module);
+                    self.filters.push((module.to_string(), level));
+                },
+                None => self.default = level,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    fn enabled(&self, level: Level, module: &str) -> bool {
+        let filter = self.filters.iter()
+            .filter(|(prefix, _)| module_matches(module, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default);
+
+        match filter {
+            Some(max) => level <= max,
+            None => false,
+        }
+    }
+
+    fn write(&mut self, level: Level, module: &str, args: fmt::Arguments) {
+        let message = args.to_string();
+
+        let _ = match &mut self.target {
+            Target::Stderr => {
+                let line = format_line(self.timestamps, level, module, &message);
+                std::io::stderr().write_all(line.as_bytes())
+            },
+            Target::File(file) => {
+                let line = format_line(self.timestamps, level, module, &message);
+                file.write_all(line.as_bytes())
+            },
+            Target::Journal(socket) => {
+                let mut datagram = Vec::new();
+                journal_field(&mut datagram, "PRIORITY", &level.priority().to_string());
+                journal_field(&mut datagram, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+                journal_field(&mut datagram, "CODE_MODULE", module);
+                journal_field(&mut datagram, "MESSAGE", &message);
+                socket.send(&datagram).map(|_| ())
+            },
+        };
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn module_matches(module: &str, prefix: &str) -> bool {
+    module == prefix
+        || (module.starts_with(prefix) && module[prefix.len()..].starts_with("::"))
+}
+
+fn format_line(timestamps: bool, level: Level, module: &str, message: &str) -> String {
+    match timestamps {
+        true => format!("{} {:<5} {}: {}\n", timestamp(), level, module, message),
+        false => format!("{:<5} {}: {}\n", level, module, message),
+    }
+}
+
+/// Formats the current time as an ISO 8601 UTC timestamp.
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        rem / 3600, rem % 3600 / 60, rem % 60,
+        now.subsec_millis())
+}
+
+/// Appends a field in the journal native protocol. Values containing
+/// newlines use the length-prefixed binary form.
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
@@ -1,20 +1,255 @@
-
+#[macro_use]
+mod log;
 mod evenger;
+mod discover;
+mod doctor;
 mod evdev;
 mod foreign;
 mod muxer;
 
-use evenger::Evenger;
+use evenger::{Evenger, Options, SinkKind};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::exit;
+
+const DEFAULT_CONFIG: &str = "/etc/evenger.conf";
+
+const USAGE: &str = "\
+usage: evenger [COMMAND] [OPTIONS]
+
+commands:
+    run                 remap input events (default)
+    monitor             run the pipeline and print every event as it is
+                        translated
+    list                list input devices and their capabilities
+    doctor              diagnose permission and environment problems
+    ctl REQUEST...      send a request to a running evenger, either as a
+                        JSON object or as a command followed by NAME=VALUE
+                        arguments, e.g. `evenger ctl status` or
+                        `evenger ctl inject actions=\"tap KEY_A\"`
+
+options:
+    --config PATH       read devices and rules from PATH
+                        (default: /etc/evenger.conf)
+    --no-grab           do not grab source devices
+    --no-output         do not create the virtual output device
+    --dry-run           neither grab source devices nor create the virtual
+                        output device, but log the output events instead
+    --socket PATH       listen for control requests on PATH, or connect to
+                        it with `ctl` (default: /run/evenger.sock)
+    --no-socket         do not listen for control requests
+    --probe-grab        with `list`, tell which devices are grabbed, by
+                        grabbing each one briefly
+
+logging options:
+    --log FILTERS       comma-separated log levels, optionally per module,
+                        e.g. `warn,evenger::srcdev=debug` (default: $EVENGER_LOG
+                        or `info`)
+    --log-timestamps    prefix log lines with a timestamp
+    --log-file PATH     append log lines to PATH instead of stderr
+    --log-journal       send log messages to the systemd journal
+
+signals:
+    INT, TERM           release held keys, ungrab devices and exit
+    HUP                 reload the configuration
+    USR1                log the state of devices and held keys";
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    exit(2);
+}
+
+enum Command {
+    Run,
+    List,
+    Doctor,
+    Ctl,
+}
 
 fn main() {
-    let mut app = Evenger::new()
-        .expect("app init failed");
-    
-    app.open_device("mouse", "/dev/input/event2")
-        .expect("can't open mouse");
-    app.open_device("keyboard", "/dev/input/event4")
-        .expect("can't open keyboard");
+    let mut args = std::env::args().skip(1).peekable();
+    let mut command = Command::Run;
+    let mut opts = Options::default();
+    let mut logger = log::Logger::new();
+    let mut config = PathBuf::from(DEFAULT_CONFIG);
+    let mut request: Vec<String> = Vec::new();
+    let mut probe_grab = false;
+
+    if let Ok(filters) = std::env::var("EVENGER_LOG") {
+        if let Err(e) = logger.parse_filters(&filters) {
+            usage_error(&format!("EVENGER_LOG: {}", e));
+        }
+    }
+
+    match args.peek().map(String::as_str) {
+        Some("run") => { args.next(); },
+        Some("monitor") => { args.next(); opts.monitor = true; },
+        Some("list") => { args.next(); command = Command::List; },
+        Some("doctor") => { args.next(); command = Command::Doctor; },
+        Some("ctl") => { args.next(); command = Command::Ctl; },
+        _ => {},
+    }
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next()
+            .unwrap_or_else(|| usage_error(&format!("{} requires an argument", name)));
 
+        match arg.as_str() {
+            "--config" => config = value("--config").into(),
+            "--no-grab" => opts.grab = false,
+            "--no-output" => opts.sink = SinkKind::Discard,
+            "--dry-run" => {
+                opts.grab = false;
+                opts.sink = SinkKind::Log;
+            },
+            "--socket" => opts.socket = Some(value("--socket").into()),
+            "--no-socket" => opts.socket = None,
+            "--probe-grab" if matches!(command, Command::List) => probe_grab = true,
+            "--log" => {
+                if let Err(e) = logger.parse_filters(&value("--log")) {
+                    usage_error(&e);
+                }
+            },
+            "--log-timestamps" => logger.set_timestamps(true),
+            "--log-file" => {
+                let path = value("--log-file");
+                match log::Target::file(&path) {
+                    Ok(target) => logger.set_target(target),
+                    Err(e) => usage_error(&format!("can't open log file {}: {}", path, e)),
+                }
+            },
+            "--log-journal" => {
+                match log::Target::journal() {
+                    Ok(target) => logger.set_target(target),
+                    Err(e) => usage_error(&format!("can't connect to the journal: {}", e)),
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if matches!(command, Command::Ctl) && !arg.starts_with("--") => request.push(arg),
+            _ => usage_error(&format!("unknown argument: {}", arg)),
+        }
+    }
+
+    log::init(logger);
+
+    match command {
+        Command::Run => {
+            if let Err(e) = run(opts, config) {
+                error!("{}", e);
+                if let evenger::Error::PermissionDenied { .. }
+                     | evenger::Error::GrabFailed { .. } = e {
+                    info!("`evenger doctor` can help diagnosing the problem");
+                }
+                exit(1);
+            }
+        },
+        Command::List => {
+            if let Err(e) = discover::print_list(probe_grab) {
+                error!("can't list input devices: {}", e);
+                exit(1);
+            }
+        },
+        Command::Doctor => {
+            if !doctor::run(config) {
+                exit(1);
+            }
+        },
+        Command::Ctl => {
+            let socket = opts.socket
+                .unwrap_or_else(|| usage_error("ctl requires a socket"));
+            let request = parse_request(&request)
+                .unwrap_or_else(|e| usage_error(&e));
+            match evenger::control_request(socket, &request) {
+                Ok(response) => {
+                    println!("{}", serde_json::to_string_pretty(&response)
+                        .unwrap_or_else(|_| response.to_string()));
+                    if response.get("ok") != Some(&Value::Bool(true)) {
+                        exit(1);
+                    }
+                },
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                },
+            }
+        },
+    }
+}
+
+/// Builds a control request from the arguments of `evenger ctl`: either a
+/// single JSON object, or a command followed by `NAME=VALUE` arguments.
+/// Values are taken as JSON if they parse as such, and as strings otherwise.
+fn parse_request(args: &[String]) -> Result<Value, String> {
+    let (command, args) = match args.split_first() {
+        Some(split) => split,
+        None => return Err("ctl requires a request".into()),
+    };
+
+    if command.starts_with('{') {
+        if !args.is_empty() {
+            return Err("unexpected arguments after a JSON request".into());
+        }
+        return serde_json::from_str(command)
+            .map_err(|e| format!("invalid request: {}", e));
+    }
+
+    let mut request = serde_json::Map::new();
+    request.insert("command".into(), command.as_str().into());
+    for arg in args {
+        let (name, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => return Err(format!("expected NAME=VALUE, found `{}`", arg)),
+        };
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| Value::String(value.to_string()));
+        request.insert(name.into(), value);
+    }
+
+    Ok(Value::Object(request))
+}
+
+fn run(opts: Options, config: PathBuf) -> evenger::Result<()> {
+    let mut app = Evenger::new(opts)?;
+    app.load_config(config)?;
     app.run()
-        .expect("error during runtime");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(args: &[&str]) -> Result<Value, String> {
+        parse_request(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn requests_from_arguments() {
+        assert_eq!(parse(&["status"]), Ok(json!({ "command": "status" })));
+        assert_eq!(parse(&["set", "name=app", "value=firefox"]),
+            Ok(json!({ "command": "set", "name": "app", "value": "firefox" })));
+        assert_eq!(parse(&["set", "name=n", "value=3", "x=true", "y=a=b"]),
+            Ok(json!({ "command": "set", "name": "n", "value": 3, "x": true, "y": "a=b" })));
+        assert_eq!(parse(&["inject", r#"actions=["tap KEY_A"]"#]),
+            Ok(json!({ "command": "inject", "actions": ["tap KEY_A"] })));
+        assert_eq!(parse(&["set", "name="]), Ok(json!({ "command": "set", "name": "" })));
+    }
+
+    #[test]
+    fn requests_as_json() {
+        assert_eq!(parse(&[r#"{"command":"layer","name":"nav"}"#]),
+            Ok(json!({ "command": "layer", "name": "nav" })));
+        assert!(parse(&[r#"{"command":"#]).unwrap_err().starts_with("invalid request: "));
+        assert_eq!(parse(&[r#"{"command":"status"}"#, "x=1"]),
+            Err("unexpected arguments after a JSON request".to_string()));
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert_eq!(parse(&[]), Err("ctl requires a request".to_string()));
+        assert_eq!(parse(&["set", "name"]), Err("expected NAME=VALUE, found `name`".to_string()));
+    }
+}
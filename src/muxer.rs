@@ -1,12 +1,15 @@
 
 use nix::sys::epoll::*;
-use std::os::unix::io::RawFd;
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::time::Duration;
 
 type Result<T> = std::result::Result<T, nix::Error>;
 
 pub struct Muxer {
     epfd: RawFd,
+    signalfd: Option<SignalFd>,
 }
 
 pub struct MuxerEvents {
@@ -21,6 +24,7 @@ impl Muxer {
     pub fn new() -> Result<Muxer> {
         Ok(Muxer {
             epfd: epoll_create()?,
+            signalfd: None,
         })
     }
 
@@ -30,6 +34,57 @@ impl Muxer {
         Ok(())
     }
 
+    /// Also watches `fd`, already watched with `watch_input`, for becoming
+    /// writable, or stops doing so.
+    pub fn watch_output(&self, fd: RawFd, watch: bool) -> Result<()> {
+        let mut flags = EpollFlags::EPOLLIN;
+        if watch {
+            flags |= EpollFlags::EPOLLOUT;
+        }
+        let mut epev = EpollEvent::new(flags, fd as u64);
+        epoll_ctl(self.epfd, EpollOp::EpollCtlMod, fd, &mut epev)?;
+        Ok(())
+    }
+
+    /// Blocks `signals` and delivers them through a signalfd instead, which
+    /// becomes readable when one of them is pending.
+    pub fn watch_signals(&mut self, signals: &[Signal]) -> Result<()> {
+        let mut mask = SigSet::empty();
+        for &signal in signals {
+            mask.add(signal);
+        }
+        mask.thread_block()?;
+
+        let signalfd = SignalFd::with_flags(&mask,
+            SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+        self.watch_input(signalfd.as_raw_fd())?;
+        self.signalfd = Some(signalfd);
+
+        Ok(())
+    }
+
+    pub fn signal_fd(&self) -> Option<RawFd> {
+        self.signalfd.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    /// Returns the next pending signal watched with `watch_signals`.
+    pub fn read_signal(&mut self) -> Result<Option<Signal>> {
+        let signalfd = match self.signalfd.as_mut() {
+            Some(signalfd) => signalfd,
+            None => return Ok(None),
+        };
+
+        loop {
+            match signalfd.read_signal()? {
+                Some(info) => match Signal::from_c_int(info.ssi_signo as i32) {
+                    Ok(signal) => return Ok(Some(signal)),
+                    Err(_) => continue,
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+
     pub fn wait(&self, timeout: Option<Duration>) -> Result<MuxerEvents> {
         let timeout_ms = match timeout {
             Some(dur) => dur.as_millis() as isize,
@@ -37,7 +92,11 @@ impl Muxer {
         };
 
         let mut events = MuxerEvents::default();
-        events.len = epoll_wait(self.epfd, &mut events.buffer, timeout_ms)?;
+        events.len = match epoll_wait(self.epfd, &mut events.buffer, timeout_ms) {
+            Ok(len) => len,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => 0,
+            Err(e) => return Err(e),
+        };
 
         Ok(events)
     }
@@ -82,6 +141,10 @@ impl MuxerEvent {
         self.0.events().contains(EpollFlags::EPOLLIN)
     }
 
+    pub fn writable(&self) -> bool {
+        self.0.events().contains(EpollFlags::EPOLLOUT)
+    }
+
     pub fn hungup(&self) -> bool {
         self.0.events().contains(EpollFlags::EPOLLHUP)
     }